@@ -0,0 +1,37 @@
+//! Times [`Node::poll_at`] on a host with many idle listening sockets,
+//! with and without the cached smoltcp poll time.
+//!
+//! Run with `cargo bench --bench poll_at`.
+
+use std::{hint::black_box, time::Instant};
+
+use skys_elvis_impl::{simulator::Node, tcp_machine::ElvOs};
+use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address};
+
+const SOCKETS: u16 = 1000;
+const CALLS: u32 = 10_000;
+
+/// Makes a host listening on `SOCKETS` ports, none of which ever connect.
+fn idle_host(poll_at_cache: bool) -> ElvOs {
+    let mut os = ElvOs::new(0, 1, EthernetAddress([0, 0, 0, 0, 0, 1]));
+    os.set_local_addrs(IpCidr::new(IpAddress::Ipv4(Ipv4Address([10, 0, 0, 1])), 24));
+    os.set_poll_at_cache(poll_at_cache);
+    for port in 1..=SOCKETS {
+        let sock = os.socket();
+        os.listen(sock, port).unwrap();
+    }
+    os.poll(0, Vec::new());
+    os
+}
+
+fn main() {
+    for poll_at_cache in [true, false] {
+        let mut os = idle_host(poll_at_cache);
+        let start = Instant::now();
+        for _ in 0..CALLS {
+            black_box(os.poll_at());
+        }
+        let per_call = start.elapsed() / CALLS;
+        println!("{SOCKETS} idle sockets, cache {poll_at_cache}: {per_call:?} per poll_at");
+    }
+}
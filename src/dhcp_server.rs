@@ -0,0 +1,201 @@
+use std::collections::{HashMap, VecDeque};
+
+use smoltcp::{
+    phy::ChecksumCapabilities,
+    wire::{
+        DhcpMessageType, DhcpPacket, DhcpRepr, EthernetAddress, EthernetFrame, EthernetProtocol,
+        EthernetRepr, IpProtocol, Ipv4Address, Ipv4Cidr, Ipv4Packet, Ipv4Repr, UdpPacket, UdpRepr,
+        DHCP_CLIENT_PORT, DHCP_SERVER_PORT,
+    },
+};
+
+use crate::log;
+use crate::simulator::{IncomingMsgs, Index, Msg, Node, OutgoingMsgs, Time};
+
+/// A minimal DHCP server, which hands out addresses from a pool.
+///
+/// It answers DISCOVERs with OFFERs and REQUESTs with ACKs (or NAKs, if the
+/// client asks for an address it wasn't given). Every reply is broadcast,
+/// since clients don't have an address to send to yet.
+/// Each client keeps the same address for the whole simulation.
+pub struct DhcpServer {
+    receiver: Index,
+    hardware_addr: EthernetAddress,
+    addr: Ipv4Cidr,
+    router: Option<Ipv4Address>,
+    /// Addresses that haven't been handed out yet.
+    pool: VecDeque<Ipv4Address>,
+    leases: HashMap<EthernetAddress, Ipv4Address>,
+    /// How long leases last, in seconds.
+    lease_duration: u32,
+}
+
+impl DhcpServer {
+    pub fn new(
+        receiver: Index,
+        hardware_addr: EthernetAddress,
+        addr: Ipv4Cidr,
+        pool: impl IntoIterator<Item = Ipv4Address>,
+    ) -> DhcpServer {
+        DhcpServer {
+            receiver,
+            hardware_addr,
+            addr,
+            router: None,
+            pool: VecDeque::from_iter(pool),
+            leases: HashMap::new(),
+            lease_duration: 3600,
+        }
+    }
+
+    /// Sets the default gateway given to clients.
+    pub fn set_router(&mut self, router: Option<Ipv4Address>) {
+        self.router = router;
+    }
+
+    /// Sets how long leases last, in seconds.
+    pub fn set_lease_duration(&mut self, seconds: u32) {
+        self.lease_duration = seconds;
+    }
+
+    /// The address given to each client.
+    pub fn leases(&self) -> &HashMap<EthernetAddress, Ipv4Address> {
+        &self.leases
+    }
+
+    /// Returns the address leased to a client,
+    /// taking a new one from the pool if it doesn't have one.
+    fn lease_for(&mut self, client: EthernetAddress) -> Option<Ipv4Address> {
+        if let Some(addr) = self.leases.get(&client) {
+            return Some(*addr);
+        }
+        let Some(addr) = self.pool.pop_front() else {
+            log!("DHCP pool is empty, ignoring {client}");
+            return None;
+        };
+        self.leases.insert(client, addr);
+        Some(addr)
+    }
+
+    /// Handles a frame, returning the reply if there is one.
+    fn handle(&mut self, frame: &[u8]) -> Option<Msg> {
+        let eth = EthernetFrame::new_checked(frame).ok()?;
+        if eth.ethertype() != EthernetProtocol::Ipv4 {
+            return None;
+        }
+        let ip = Ipv4Packet::new_checked(eth.payload()).ok()?;
+        if ip.next_header() != IpProtocol::Udp {
+            return None;
+        }
+        let udp = UdpPacket::new_checked(ip.payload()).ok()?;
+        if udp.dst_port() != DHCP_SERVER_PORT {
+            return None;
+        }
+        let dhcp = DhcpPacket::new_checked(udp.payload()).ok()?;
+        let request = DhcpRepr::parse(&dhcp).ok()?;
+        let client = request.client_hardware_address;
+
+        let (message_type, your_ip) = match request.message_type {
+            DhcpMessageType::Discover => (DhcpMessageType::Offer, self.lease_for(client)?),
+            DhcpMessageType::Request => {
+                // the client picked a different server
+                if request
+                    .server_identifier
+                    .is_some_and(|id| id != self.addr.address())
+                {
+                    return None;
+                }
+                let lease = self.lease_for(client)?;
+                match request.requested_ip {
+                    Some(requested) if requested != lease => {
+                        (DhcpMessageType::Nak, Ipv4Address::UNSPECIFIED)
+                    }
+                    _ => (DhcpMessageType::Ack, lease),
+                }
+            }
+            _ => return None,
+        };
+        log!("DHCP {message_type:?} {your_ip} to {client}");
+
+        let reply = DhcpRepr {
+            message_type,
+            transaction_id: request.transaction_id,
+            secs: 0,
+            client_hardware_address: client,
+            client_ip: Ipv4Address::UNSPECIFIED,
+            your_ip,
+            server_ip: self.addr.address(),
+            router: self.router,
+            subnet_mask: Some(self.addr.netmask()),
+            relay_agent_ip: Ipv4Address::UNSPECIFIED,
+            broadcast: false,
+            requested_ip: None,
+            client_identifier: None,
+            server_identifier: Some(self.addr.address()),
+            parameter_request_list: None,
+            dns_servers: None,
+            max_size: None,
+            lease_duration: Some(self.lease_duration),
+            renew_duration: None,
+            rebind_duration: None,
+            additional_options: &[],
+        };
+        Some(self.emit(&reply))
+    }
+
+    /// Puts a DHCP message in a broadcast ethernet frame.
+    fn emit(&self, dhcp_repr: &DhcpRepr) -> Msg {
+        let udp_repr = UdpRepr {
+            src_port: DHCP_SERVER_PORT,
+            dst_port: DHCP_CLIENT_PORT,
+        };
+        let ip_repr = Ipv4Repr {
+            src_addr: self.addr.address(),
+            dst_addr: Ipv4Address::BROADCAST,
+            next_header: IpProtocol::Udp,
+            payload_len: udp_repr.header_len() + dhcp_repr.buffer_len(),
+            hop_limit: 64,
+        };
+        let eth_repr = EthernetRepr {
+            src_addr: self.hardware_addr,
+            dst_addr: EthernetAddress::BROADCAST,
+            ethertype: EthernetProtocol::Ipv4,
+        };
+
+        let checksums = ChecksumCapabilities::default();
+        let mut msg = vec![0; eth_repr.buffer_len() + ip_repr.buffer_len() + ip_repr.payload_len];
+        let mut eth = EthernetFrame::new_unchecked(&mut msg[..]);
+        eth_repr.emit(&mut eth);
+        let mut ip = Ipv4Packet::new_unchecked(eth.payload_mut());
+        ip_repr.emit(&mut ip, &checksums);
+        let mut udp = UdpPacket::new_unchecked(ip.payload_mut());
+        udp_repr.emit(
+            &mut udp,
+            &ip_repr.src_addr.into(),
+            &ip_repr.dst_addr.into(),
+            dhcp_repr.buffer_len(),
+            |payload| {
+                let mut dhcp = DhcpPacket::new_unchecked(payload);
+                dhcp_repr
+                    .emit(&mut dhcp)
+                    .expect("buffer should fit the DHCP message");
+            },
+            &checksums,
+        );
+        msg
+    }
+}
+
+impl Node for DhcpServer {
+    fn poll(&mut self, _time: Time, incoming: IncomingMsgs) -> OutgoingMsgs {
+        let receiver = self.receiver;
+        let replies = incoming
+            .into_iter()
+            .filter_map(|(_sender, msg)| self.handle(&msg));
+        Vec::from_iter(replies.map(|reply| (receiver, reply)))
+    }
+
+    fn poll_at(&mut self) -> Option<Time> {
+        None
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::VecDeque;
+
+use crate::log;
+use crate::packet::is_pure_ack;
+use crate::rng::Rng;
+use crate::simulator::{IncomingMsgs, Index, Msg, Node, OutgoingMsgs, Time};
+
+/// A node that sits between two others and passes messages through,
+/// except that it can lose or delay pure ACKs (segments with the ACK flag
+/// and nothing else to say). Everything else goes through right away.
+///
+/// Disrupting only ACKs messes with the sender's clock without losing any
+/// data, which tests different behavior than losing packets in general.
+pub struct Filter {
+    end1: Index,
+    end2: Index,
+    rng: Rng,
+    /// The probability that a pure ACK is lost.
+    ack_loss: f64,
+    /// How long pure ACKs are held before being passed on.
+    ack_delay: Time,
+    /// Delayed ACKs, along with when to pass them on and where to.
+    delayed: VecDeque<(Time, Index, Msg)>,
+    /// The number of pure ACKs lost.
+    acks_lost: usize,
+}
+
+impl Filter {
+    /// Creates a filter between `end1` and `end2` that passes everything
+    /// through. Losses are drawn from `rng`.
+    pub fn new(end1: Index, end2: Index, rng: Rng) -> Filter {
+        Filter {
+            end1,
+            end2,
+            rng,
+            ack_loss: 0.0,
+            ack_delay: 0,
+            delayed: VecDeque::new(),
+            acks_lost: 0,
+        }
+    }
+
+    /// Sets the probability that each pure ACK is lost.
+    pub fn set_ack_loss(&mut self, probability: f64) {
+        assert!((0.0..=1.0).contains(&probability));
+        self.ack_loss = probability;
+    }
+
+    /// Sets how long pure ACKs are held before they're passed on.
+    /// ACKs can be overtaken by other messages while they're held.
+    pub fn set_ack_delay(&mut self, delay: Time) {
+        assert!(delay >= 0);
+        self.ack_delay = delay;
+    }
+
+    /// The number of pure ACKs that were lost.
+    pub fn acks_lost(&self) -> usize {
+        self.acks_lost
+    }
+}
+
+impl Node for Filter {
+    fn poll(&mut self, time: Time, incoming: IncomingMsgs) -> OutgoingMsgs {
+        let mut result = Vec::new();
+        for (sender, msg) in incoming {
+            let dest = if sender == self.end1 {
+                self.end2
+            } else if sender == self.end2 {
+                self.end1
+            } else {
+                panic!("Tried to send to invalid machine")
+            };
+
+            if !is_pure_ack(&msg) {
+                result.push((dest, msg));
+            } else if self.rng.chance(self.ack_loss) {
+                log!("filter lost an ACK from {sender}");
+                self.acks_lost += 1;
+            } else {
+                self.delayed.push_back((time + self.ack_delay, dest, msg));
+            }
+        }
+
+        while let Some((release_time, _, _)) = self.delayed.front() {
+            if *release_time <= time {
+                let (_, dest, msg) = self.delayed.pop_front().unwrap();
+                result.push((dest, msg));
+            } else {
+                break;
+            }
+        }
+        result
+    }
+
+    fn poll_at(&mut self) -> Option<Time> {
+        self.delayed.front().map(|(time, _, _)| *time)
+    }
+
+    fn is_link(&self) -> bool {
+        true
+    }
+}
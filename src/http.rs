@@ -0,0 +1,161 @@
+//! A tiny HTTP/1.0 client and server, to show the stack doing
+//! something recognizable.
+//!
+//! Both are nodes that wrap an [`ElvOs`] and run the application
+//! after every poll, instead of using callbacks.
+
+use smoltcp::{
+    iface::SocketHandle,
+    socket::tcp::State,
+    wire::{IpEndpoint, IpListenEndpoint},
+};
+
+use crate::simulator::{IncomingMsgs, Node, OutgoingMsgs, Time};
+use crate::tcp_machine::{ElvError, ElvOs};
+
+pub const REQUEST: &[u8] = b"GET / HTTP/1.0\r\n\r\n";
+
+/// Sends as much of `pending` as the socket will take.
+fn send_pending(os: &mut ElvOs, sock: SocketHandle, pending: &mut Vec<u8>) {
+    // even an empty send makes smoltcp want to be polled right away
+    if pending.is_empty() {
+        return;
+    }
+    if let Ok(sent) = os.send(sock, pending) {
+        pending.drain(..sent);
+    }
+}
+
+/// Serves a fixed body to one client, then closes the connection.
+pub struct HttpServer {
+    os: ElvOs,
+    sock: SocketHandle,
+    request: Vec<u8>,
+    /// The rest of the response that hasn't been sent yet.
+    pending: Vec<u8>,
+    body: Vec<u8>,
+    responded: bool,
+}
+
+impl HttpServer {
+    pub fn new(
+        mut os: ElvOs,
+        endpoint: impl Into<IpListenEndpoint>,
+        body: &[u8],
+    ) -> Result<HttpServer, ElvError> {
+        let sock = os.socket();
+        os.listen(sock, endpoint)?;
+        Ok(HttpServer {
+            os,
+            sock,
+            request: Vec::new(),
+            pending: Vec::new(),
+            body: body.to_vec(),
+            responded: false,
+        })
+    }
+
+    pub fn os(&mut self) -> &mut ElvOs {
+        &mut self.os
+    }
+
+    fn run_app(&mut self) {
+        self.request.append(&mut self.os.recv(self.sock));
+        let request_done = self.request.windows(4).any(|w| w == b"\r\n\r\n");
+        if request_done && !self.responded {
+            self.responded = true;
+            self.pending = format!(
+                "HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n",
+                self.body.len()
+            )
+            .into_bytes();
+            self.pending.extend(&self.body);
+        }
+
+        send_pending(&mut self.os, self.sock, &mut self.pending);
+        if self.responded && self.pending.is_empty() && self.os.may_send(self.sock) {
+            self.os.close(self.sock);
+        }
+    }
+}
+
+impl Node for HttpServer {
+    fn poll(&mut self, time: Time, incoming: IncomingMsgs) -> OutgoingMsgs {
+        let outgoing = self.os.poll(time, incoming);
+        self.run_app();
+        outgoing
+    }
+
+    fn poll_at(&mut self) -> Option<Time> {
+        self.os.poll_at()
+    }
+}
+
+/// Requests `/` from a server, and keeps the response.
+pub struct HttpClient {
+    os: ElvOs,
+    sock: SocketHandle,
+    /// The rest of the request that hasn't been sent yet.
+    pending: Vec<u8>,
+    response: Vec<u8>,
+    done: bool,
+}
+
+impl HttpClient {
+    /// Creates a client and starts connecting to the server.
+    pub fn new(
+        mut os: ElvOs,
+        local: impl Into<IpListenEndpoint>,
+        server: impl Into<IpEndpoint>,
+    ) -> Result<HttpClient, ElvError> {
+        let sock = os.socket();
+        os.connect(sock, local, server)?;
+        Ok(HttpClient {
+            os,
+            sock,
+            pending: REQUEST.to_vec(),
+            response: Vec::new(),
+            done: false,
+        })
+    }
+
+    pub fn os(&mut self) -> &mut ElvOs {
+        &mut self.os
+    }
+
+    /// The whole response, once the server has closed the connection.
+    pub fn response(&self) -> Option<&[u8]> {
+        self.done.then_some(&self.response[..])
+    }
+
+    /// The body of the response, once the server has closed the connection.
+    pub fn body(&self) -> Option<&[u8]> {
+        let response = self.response()?;
+        let header_end = response.windows(4).position(|w| w == b"\r\n\r\n")?;
+        Some(&response[header_end + 4..])
+    }
+
+    fn run_app(&mut self) {
+        if self.done || self.os.state(self.sock) == State::SynSent {
+            return;
+        }
+        send_pending(&mut self.os, self.sock, &mut self.pending);
+        self.response.append(&mut self.os.recv(self.sock));
+        if !self.os.may_recv(self.sock) {
+            self.done = true;
+            self.os.close(self.sock);
+        }
+    }
+}
+
+impl Node for HttpClient {
+    fn poll(&mut self, time: Time, incoming: IncomingMsgs) -> OutgoingMsgs {
+        let outgoing = self.os.poll(time, incoming);
+        self.run_app();
+        outgoing
+    }
+
+    fn poll_at(&mut self) -> Option<Time> {
+        self.os.poll_at()
+    }
+}
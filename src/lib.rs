@@ -0,0 +1,22 @@
+pub mod dhcp_server;
+pub mod filter;
+pub mod http;
+pub mod packet;
+pub mod pcap;
+pub mod rng;
+pub mod server;
+pub mod shared_medium;
+pub mod simulator;
+pub mod stream;
+pub mod tcp_machine;
+pub mod testing;
+pub mod topology;
+pub mod wire;
+
+/// Similar to println, but it also prints the file and line number.
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => {
+        println!("[{}:{}] {}", file!(), line!(), format!($($arg)*))
+    }
+}
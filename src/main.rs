@@ -1,27 +1,13 @@
 use core::str;
 
-use simulator::run_sim_until;
+use skys_elvis_impl::{log, simulator::run_sim_until, tcp_machine::ElvOs, wire::Wire};
 use smoltcp::{
     iface::SocketHandle,
     wire::{EthernetAddress, IpAddress, IpCidr, IpEndpoint, Ipv4Address},
 };
-use tcp_machine::ElvOs;
-use wire::Wire;
-
-mod simulator;
-mod tcp_machine;
-mod wire;
 
 const MILLISECOND: i64 = 1000;
 
-/// Similar to println, but it also prints the file and line number.
-#[macro_export]
-macro_rules! log {
-    ($($arg:tt)*) => {
-        println!("[{}:{}] {}", file!(), line!(), format!($($arg)*))
-    }
-}
-
 fn main() {
     let time = 0;
     let end_time = 1000 * MILLISECOND;
@@ -43,7 +29,9 @@ fn main() {
         node0.set_connect_callback(sock, send_ping_callback);
 
         let cb_event = move |elvos: &mut ElvOs| {
-            elvos.connect(sock, END0, END1);
+            elvos
+                .connect(sock, END0, END1)
+                .expect("connect should succeed");
         };
         node0.add_event(MILLISECOND * 45, cb_event);
     }
@@ -53,7 +41,7 @@ fn main() {
         node1.set_local_addrs(IpCidr::new(END1.addr, 24));
         let sock = node1.socket();
         node1.set_recv_callback(sock, ping_pong_callback);
-        node1.listen(sock, END1);
+        node1.listen(sock, END1).expect("listen should succeed");
     }
 
     run_sim_until(&mut [&mut node0, &mut node1, &mut wire], end_time);
@@ -0,0 +1,145 @@
+//! Helpers for looking inside the messages nodes send each other,
+//! assuming they are ethernet frames.
+//!
+//! smoltcp doesn't support TCP urgent data: it never sets the URG flag,
+//! and treats urgent bytes it receives as ordinary data. The URG helpers
+//! here let a middlebox (or a test) set and check the flag anyway.
+
+use smoltcp::{
+    phy::ChecksumCapabilities,
+    wire::{
+        EthernetFrame, EthernetProtocol, EthernetRepr, IpAddress, IpEndpoint, IpProtocol,
+        Ipv4Packet, Ipv4Repr, TcpPacket, TcpSeqNumber,
+    },
+};
+
+use crate::simulator::Msg;
+
+/// Returns the TCP segment inside an ethernet-ip-tcp frame,
+/// or `None` if the frame doesn't hold one.
+pub fn tcp_segment(frame: &[u8]) -> Option<TcpPacket<&[u8]>> {
+    let (start, end) = tcp_range(frame)?;
+    TcpPacket::new_checked(&frame[start..end]).ok()
+}
+
+/// Returns the source and destination endpoints of the TCP segment
+/// in the frame, which identify the connection it belongs to.
+pub fn tcp_flow(frame: &[u8]) -> Option<(IpEndpoint, IpEndpoint)> {
+    let tcp = tcp_segment(frame)?;
+    let ip = Ipv4Packet::new_unchecked(&frame[EthernetFrame::<&[u8]>::header_len()..]);
+    Some((
+        IpEndpoint::new(ip.src_addr().into(), tcp.src_port()),
+        IpEndpoint::new(ip.dst_addr().into(), tcp.dst_port()),
+    ))
+}
+
+/// Whether the frame holds a pure ACK: a TCP segment with the ACK flag,
+/// no other flags, and no data.
+pub fn is_pure_ack(frame: &[u8]) -> bool {
+    tcp_segment(frame).is_some_and(|tcp| {
+        tcp.ack() && !tcp.syn() && !tcp.fin() && !tcp.rst() && tcp.payload().is_empty()
+    })
+}
+
+/// Returns the urgent pointer of the TCP segment in the frame,
+/// if it has the URG flag set.
+pub fn urgent_pointer(frame: &[u8]) -> Option<u16> {
+    let tcp = tcp_segment(frame)?;
+    tcp.urg().then(|| tcp.urgent_at())
+}
+
+/// Sets the URG flag and urgent pointer of the TCP segment in the frame,
+/// and fixes its checksum. `None` clears the flag.
+///
+/// Returns false if the frame doesn't hold a TCP segment.
+pub fn set_urgent(frame: &mut [u8], pointer: Option<u16>) -> bool {
+    let Some((start, end)) = tcp_range(frame) else {
+        return false;
+    };
+    let (src_addr, dst_addr) = {
+        let ip = Ipv4Packet::new_unchecked(&frame[EthernetFrame::<&[u8]>::header_len()..]);
+        (
+            IpAddress::Ipv4(ip.src_addr()),
+            IpAddress::Ipv4(ip.dst_addr()),
+        )
+    };
+    let mut tcp = TcpPacket::new_unchecked(&mut frame[start..end]);
+    tcp.set_urg(pointer.is_some());
+    tcp.set_urgent_at(pointer.unwrap_or(0));
+    tcp.fill_checksum(&src_addr, &dst_addr);
+    true
+}
+
+/// Builds a RST answering the TCP segment in the frame, the way a host
+/// refuses a connection: it goes back to the sender and acknowledges
+/// everything the segment carried.
+///
+/// Returns `None` if the frame doesn't hold a TCP segment, or holds a RST
+/// (which is never answered).
+pub fn reset_reply(frame: &[u8]) -> Option<Msg> {
+    let tcp = tcp_segment(frame)?;
+    if tcp.rst() {
+        return None;
+    }
+    let eth = EthernetFrame::new_unchecked(frame);
+    let ip = Ipv4Packet::new_unchecked(eth.payload());
+    let mut ack = tcp.seq_number() + tcp.payload().len();
+    if tcp.syn() {
+        ack += 1;
+    }
+    if tcp.fin() {
+        ack += 1;
+    }
+
+    let eth_repr = EthernetRepr {
+        src_addr: eth.dst_addr(),
+        dst_addr: eth.src_addr(),
+        ethertype: EthernetProtocol::Ipv4,
+    };
+    let ip_repr = Ipv4Repr {
+        src_addr: ip.dst_addr(),
+        dst_addr: ip.src_addr(),
+        next_header: IpProtocol::Tcp,
+        payload_len: 20,
+        hop_limit: 64,
+    };
+    let mut msg = vec![0; eth_repr.buffer_len() + ip_repr.buffer_len() + ip_repr.payload_len];
+    let mut reply_eth = EthernetFrame::new_unchecked(&mut msg[..]);
+    eth_repr.emit(&mut reply_eth);
+    let mut reply_ip = Ipv4Packet::new_unchecked(reply_eth.payload_mut());
+    ip_repr.emit(&mut reply_ip, &ChecksumCapabilities::default());
+    let mut reply = TcpPacket::new_unchecked(reply_ip.payload_mut());
+    reply.set_src_port(tcp.dst_port());
+    reply.set_dst_port(tcp.src_port());
+    reply.set_seq_number(if tcp.ack() {
+        tcp.ack_number()
+    } else {
+        TcpSeqNumber(0)
+    });
+    reply.set_ack_number(ack);
+    reply.set_header_len(20);
+    reply.clear_flags();
+    reply.set_rst(true);
+    reply.set_ack(true);
+    reply.set_window_len(0);
+    reply.set_urgent_at(0);
+    reply.fill_checksum(&ip_repr.src_addr.into(), &ip_repr.dst_addr.into());
+    Some(msg)
+}
+
+/// Finds where the TCP segment is in a frame.
+fn tcp_range(frame: &[u8]) -> Option<(usize, usize)> {
+    let eth = EthernetFrame::new_checked(frame).ok()?;
+    if eth.ethertype() != EthernetProtocol::Ipv4 {
+        return None;
+    }
+    let ip = Ipv4Packet::new_checked(eth.payload()).ok()?;
+    if ip.next_header() != IpProtocol::Tcp {
+        return None;
+    }
+    let ip_start = EthernetFrame::<&[u8]>::header_len();
+    let start = ip_start + usize::from(ip.header_len());
+    let end = ip_start + usize::from(ip.total_len());
+    TcpPacket::new_checked(&frame[start..end]).ok()?;
+    Some((start, end))
+}
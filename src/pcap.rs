@@ -0,0 +1,149 @@
+//! Reading captured traffic from `.pcap` files, and replaying it
+//! into a simulation.
+
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind},
+    path::Path,
+};
+
+use crate::log;
+use crate::simulator::{IncomingMsgs, Index, Msg, Node, OutgoingMsgs, Time};
+
+/// The link type of ethernet frames.
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// Parses a pcap file of ethernet frames.
+///
+/// Returns each frame along with its timestamp in microseconds,
+/// relative to the first frame. It's an error for a frame to be older
+/// than the one before it, since replaying it would mean going back in time.
+pub fn parse_pcap(data: &[u8]) -> std::io::Result<Vec<(Time, Msg)>> {
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+
+    if data.len() < 24 {
+        return Err(invalid("pcap file is too short"));
+    }
+    let magic: [u8; 4] = data[0..4].try_into().unwrap();
+    // the magic number tells us the byte order,
+    // and whether timestamps have microseconds or nanoseconds
+    let (big_endian, nanos) = match magic {
+        [0xd4, 0xc3, 0xb2, 0xa1] => (false, false),
+        [0xa1, 0xb2, 0xc3, 0xd4] => (true, false),
+        [0x4d, 0x3c, 0xb2, 0xa1] => (false, true),
+        [0xa1, 0xb2, 0x3c, 0x4d] => (true, true),
+        _ => return Err(invalid("not a pcap file")),
+    };
+    let read_u32 = |bytes: &[u8]| {
+        let bytes: [u8; 4] = bytes.try_into().unwrap();
+        if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    };
+    if read_u32(&data[20..24]) != LINKTYPE_ETHERNET {
+        return Err(invalid("pcap file doesn't hold ethernet frames"));
+    }
+
+    let mut frames: Vec<(Time, Msg)> = Vec::new();
+    let mut rest = &data[24..];
+    while !rest.is_empty() {
+        if rest.len() < 16 {
+            return Err(invalid("pcap record header is cut off"));
+        }
+        let secs = Time::from(read_u32(&rest[0..4]));
+        let fraction = Time::from(read_u32(&rest[4..8]));
+        let len = read_u32(&rest[8..12]) as usize;
+        rest = &rest[16..];
+        if rest.len() < len {
+            return Err(invalid("pcap record is cut off"));
+        }
+
+        let micros = if nanos { fraction / 1000 } else { fraction };
+        let time = secs * 1_000_000 + micros;
+        if frames.last().is_some_and(|&(last, _)| time < last) {
+            return Err(invalid("pcap record is older than the one before it"));
+        }
+        frames.push((time, rest[..len].to_vec()));
+        rest = &rest[len..];
+    }
+
+    if let Some(&(first, _)) = frames.first() {
+        for (time, _frame) in &mut frames {
+            *time -= first;
+        }
+    }
+    Ok(frames)
+}
+
+/// A node that sends frames from a capture at the times they were recorded,
+/// as if a real host was on the other end of its wire.
+///
+/// It ignores everything sent to it, so it can only replay one side of a
+/// conversation, and won't react if the other side does something different.
+pub struct PcapReplayNode {
+    receiver: Index,
+    /// The frames that haven't been sent yet, and when to send them.
+    frames: VecDeque<(Time, Msg)>,
+}
+
+impl PcapReplayNode {
+    /// Creates a node that sends `frames` to `receiver`.
+    /// Each frame is sent at `start` plus its timestamp.
+    pub fn new(
+        receiver: Index,
+        start: Time,
+        frames: impl IntoIterator<Item = (Time, Msg)>,
+    ) -> PcapReplayNode {
+        let mut frames = Vec::from_iter(frames);
+        frames.sort_by_key(|(time, _frame)| *time);
+        PcapReplayNode {
+            receiver,
+            frames: VecDeque::from_iter(
+                frames
+                    .into_iter()
+                    .map(|(time, frame)| (start + time, frame)),
+            ),
+        }
+    }
+
+    /// Creates a node that replays the ethernet frames in a pcap file.
+    /// See [`new`](PcapReplayNode::new).
+    pub fn from_file(
+        receiver: Index,
+        start: Time,
+        path: impl AsRef<Path>,
+    ) -> std::io::Result<PcapReplayNode> {
+        let data = std::fs::read(path)?;
+        Ok(PcapReplayNode::new(receiver, start, parse_pcap(&data)?))
+    }
+
+    /// The number of frames that haven't been sent yet.
+    pub fn remaining(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+impl Node for PcapReplayNode {
+    fn poll(&mut self, time: Time, incoming: IncomingMsgs) -> OutgoingMsgs {
+        for (sender, _msg) in incoming {
+            log!("replay node ignoring message from {sender}");
+        }
+
+        let mut result = Vec::new();
+        while let Some((frame_time, _)) = self.frames.front() {
+            if *frame_time <= time {
+                let (_, frame) = self.frames.pop_front().unwrap();
+                result.push((self.receiver, frame));
+            } else {
+                break;
+            }
+        }
+        result
+    }
+
+    fn poll_at(&mut self) -> Option<Time> {
+        self.frames.front().map(|(time, _frame)| *time)
+    }
+}
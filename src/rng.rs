@@ -0,0 +1,55 @@
+/// A small, seeded pseudorandom number generator (splitmix64).
+///
+/// Everything random in a simulation should come from one of these,
+/// so a whole run can be reproduced from its seed.
+/// Use [`fork`](Rng::fork) to give each node its own generator
+/// that is still derived from the central seed.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    /// Makes a new generator seeded from this one.
+    pub fn fork(&mut self) -> Rng {
+        Rng::new(self.next_u64())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns true with the given probability.
+    pub fn chance(&mut self, probability: f64) -> bool {
+        self.next_f64() < probability
+    }
+
+    /// Returns a number in `[low, high]`.
+    pub fn range(&mut self, low: i64, high: i64) -> i64 {
+        assert!(low <= high);
+        let width = (high - low) as u64 + 1;
+        low + (self.next_u64() % width) as i64
+    }
+
+    /// Returns a number from a normal distribution (Box-Muller).
+    pub fn normal(&mut self, mean: f64, std_dev: f64) -> f64 {
+        // 1 - x so the log never sees 0
+        let u1 = 1.0 - self.next_f64();
+        let u2 = self.next_f64();
+        let z = (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos();
+        mean + z * std_dev
+    }
+}
@@ -0,0 +1,197 @@
+//! A server that handles many connections at once, each with its own
+//! handler.
+//!
+//! smoltcp has no backlog: a listening socket becomes the connection when a
+//! SYN arrives. So the server keeps a few sockets listening on the same
+//! endpoint, and replaces each one as it's taken by a connection.
+
+use smoltcp::{
+    iface::SocketHandle,
+    socket::tcp::State,
+    wire::{IpEndpoint, IpListenEndpoint},
+};
+
+use crate::simulator::{IncomingMsgs, Node, OutgoingMsgs, Time};
+use crate::tcp_machine::{ElvError, ElvOs};
+
+/// The application side of one connection.
+pub trait ConnectionHandler {
+    /// Called with data received on the connection.
+    /// Returns data to send back, which may be empty.
+    fn on_recv(&mut self, data: &[u8]) -> Vec<u8>;
+
+    /// Called when the connection is closed, just before it's removed.
+    fn on_close(&mut self) {}
+}
+
+/// Makes the handler for a new connection, given the peer's endpoint.
+pub type HandlerFactory = dyn FnMut(IpEndpoint) -> Box<dyn ConnectionHandler>;
+
+/// A connection being handled by a [`ConnectionManager`].
+pub struct Connection {
+    sock: SocketHandle,
+    peer: IpEndpoint,
+    handler: Box<dyn ConnectionHandler>,
+    /// Data from the handler that hasn't been sent yet.
+    pending: Vec<u8>,
+    bytes_received: usize,
+    bytes_sent: usize,
+}
+
+impl Connection {
+    pub fn sock(&self) -> SocketHandle {
+        self.sock
+    }
+
+    pub fn peer(&self) -> IpEndpoint {
+        self.peer
+    }
+
+    /// The number of bytes received and given to the handler.
+    pub fn bytes_received(&self) -> usize {
+        self.bytes_received
+    }
+
+    /// The number of bytes from the handler put in the socket.
+    pub fn bytes_sent(&self) -> usize {
+        self.bytes_sent
+    }
+}
+
+/// Accepts connections on an endpoint, gives each one a handler, and
+/// removes their sockets once they're closed.
+///
+/// After the peer closes its side of a connection, the manager closes its
+/// side once everything the handler returned has been sent.
+pub struct ConnectionManager {
+    os: ElvOs,
+    endpoint: IpListenEndpoint,
+    /// The sockets waiting for connections.
+    listeners: Vec<SocketHandle>,
+    connections: Vec<Connection>,
+    new_handler: Box<HandlerFactory>,
+    /// The number of connections that have been closed and removed.
+    closed: usize,
+}
+
+impl ConnectionManager {
+    /// Creates a manager that accepts connections on `endpoint`, and calls
+    /// `new_handler` to make the handler for each one.
+    ///
+    /// `backlog` sockets listen at a time, which is how many connections
+    /// can arrive between two polls of this node.
+    pub fn new(
+        os: ElvOs,
+        endpoint: impl Into<IpListenEndpoint>,
+        backlog: usize,
+        new_handler: impl FnMut(IpEndpoint) -> Box<dyn ConnectionHandler> + 'static,
+    ) -> Result<ConnectionManager, ElvError> {
+        assert!(backlog > 0, "backlog must be positive");
+        let mut manager = ConnectionManager {
+            os,
+            endpoint: endpoint.into(),
+            listeners: Vec::new(),
+            connections: Vec::new(),
+            new_handler: Box::new(new_handler),
+            closed: 0,
+        };
+        for _ in 0..backlog {
+            manager.add_listener()?;
+        }
+        Ok(manager)
+    }
+
+    pub fn os(&mut self) -> &mut ElvOs {
+        &mut self.os
+    }
+
+    /// The connections that are open (or being opened or closed).
+    pub fn connections(&self) -> impl Iterator<Item = &Connection> {
+        self.connections.iter()
+    }
+
+    /// The number of connections that have been closed and removed.
+    pub fn closed(&self) -> usize {
+        self.closed
+    }
+
+    fn add_listener(&mut self) -> Result<(), ElvError> {
+        let sock = self.os.socket();
+        self.os.listen(sock, self.endpoint)?;
+        self.listeners.push(sock);
+        Ok(())
+    }
+
+    fn run_app(&mut self) {
+        // listeners that got a SYN become connections
+        let mut i = 0;
+        while i < self.listeners.len() {
+            let sock = self.listeners[i];
+            if self.os.state(sock) == State::Listen {
+                i += 1;
+                continue;
+            }
+            self.listeners.swap_remove(i);
+            self.add_listener().expect("listening again should succeed");
+            let Some(peer) = self.os.remote_endpoint(sock) else {
+                // the handshake failed already
+                self.os.remove_socket(sock);
+                continue;
+            };
+            self.connections.push(Connection {
+                sock,
+                peer,
+                handler: (self.new_handler)(peer),
+                pending: Vec::new(),
+                bytes_received: 0,
+                bytes_sent: 0,
+            });
+        }
+
+        for conn in &mut self.connections {
+            let data = self.os.recv(conn.sock);
+            if !data.is_empty() {
+                conn.bytes_received += data.len();
+                let reply = conn.handler.on_recv(&data);
+                conn.pending.extend(reply);
+            }
+            // even an empty send makes smoltcp want to be polled right away
+            if !conn.pending.is_empty() {
+                if let Ok(sent) = self.os.send(conn.sock, &conn.pending) {
+                    conn.pending.drain(..sent);
+                    conn.bytes_sent += sent;
+                }
+            }
+            let state = self.os.state(conn.sock);
+            let peer_closed = state != State::SynReceived && !self.os.may_recv(conn.sock);
+            if peer_closed && conn.pending.is_empty() && self.os.may_send(conn.sock) {
+                self.os.close(conn.sock);
+            }
+        }
+
+        // remove connections that are done
+        let os = &mut self.os;
+        let before = self.connections.len();
+        self.connections.retain_mut(|conn| {
+            if os.state(conn.sock) != State::Closed {
+                return true;
+            }
+            conn.handler.on_close();
+            os.remove_socket(conn.sock);
+            false
+        });
+        self.closed += before - self.connections.len();
+    }
+}
+
+impl Node for ConnectionManager {
+    fn poll(&mut self, time: Time, incoming: IncomingMsgs) -> OutgoingMsgs {
+        let outgoing = self.os.poll(time, incoming);
+        self.run_app();
+        outgoing
+    }
+
+    fn poll_at(&mut self) -> Option<Time> {
+        self.os.poll_at()
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+
+use crate::log;
+use crate::simulator::{IncomingMsgs, Index, Msg, Node, OutgoingMsgs, Time};
+
+/// A frame being sent over the medium.
+struct Transmission {
+    sender: Index,
+    start: Time,
+    end: Time,
+    msg: Msg,
+    collided: bool,
+}
+
+impl Transmission {
+    fn overlaps(&self, other: &Transmission) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+/// A half-duplex medium shared by several nodes, like classic Ethernet.
+///
+/// Every frame takes time to transmit, based on its size and the bandwidth.
+/// If two different nodes transmit at overlapping times, the frames collide
+/// and neither is delivered. There is no carrier sense, so nodes find out
+/// about collisions the same way TCP does: nothing arrives.
+///
+/// Frames that don't collide are delivered to every other attached node.
+pub struct SharedMedium {
+    ends: Vec<Index>,
+    delay: Time,
+    /// Bandwidth in bits per second.
+    bandwidth: u64,
+    /// Frames that are being transmitted or propagated.
+    transmissions: Vec<Transmission>,
+    /// The time each node finishes sending its last frame.
+    /// A node's own frames are sent one after the other.
+    busy_until: HashMap<Index, Time>,
+    collisions: usize,
+    /// Two sets of nodes that can't reach each other, if the medium is partitioned.
+    partition: Option<(Vec<Index>, Vec<Index>)>,
+}
+
+impl SharedMedium {
+    pub fn new(ends: Vec<Index>, delay: Time, bandwidth: u64) -> SharedMedium {
+        assert!(delay >= 0);
+        assert!(bandwidth > 0);
+        SharedMedium {
+            ends,
+            delay,
+            bandwidth,
+            transmissions: Vec::new(),
+            busy_until: HashMap::new(),
+            collisions: 0,
+            partition: None,
+        }
+    }
+
+    /// Attaches another node to the medium, e.g. a host that joined the
+    /// simulation with [`Simulation::add_node`](crate::simulator::Simulation::add_node).
+    /// It gets every frame that finishes arriving from now on.
+    pub fn attach(&mut self, end: Index) {
+        if !self.ends.contains(&end) {
+            self.ends.push(end);
+        }
+    }
+
+    /// The number of collisions that have happened on this medium.
+    pub fn collisions(&self) -> usize {
+        self.collisions
+    }
+
+    /// Splits the attached nodes into two sets. Frames sent from one set
+    /// are no longer delivered to nodes in the other set, although they
+    /// still take up the medium (and can still collide).
+    /// Nodes in neither set can still reach everyone.
+    ///
+    /// This replaces any earlier partition.
+    pub fn partition(&mut self, set_a: &[Index], set_b: &[Index]) {
+        self.partition = Some((set_a.to_vec(), set_b.to_vec()));
+    }
+
+    /// Removes the partition, so every node can reach every other node again.
+    pub fn heal(&mut self) {
+        self.partition = None;
+    }
+
+    /// Whether the partition stops frames from `sender` reaching `dest`.
+    fn separated(&self, sender: Index, dest: Index) -> bool {
+        match &self.partition {
+            Some((a, b)) => {
+                (a.contains(&sender) && b.contains(&dest))
+                    || (b.contains(&sender) && a.contains(&dest))
+            }
+            None => false,
+        }
+    }
+
+    /// How long it takes to put a message of the given length on the medium.
+    fn transmit_time(&self, len: usize) -> Time {
+        let bits = len as u64 * 8;
+        let micros = (bits * 1_000_000).div_ceil(self.bandwidth);
+        micros as Time
+    }
+
+    /// Starts transmitting a message, and marks any frames it collides with.
+    fn transmit(&mut self, time: Time, sender: Index, msg: Msg) {
+        let start = Time::max(time, self.busy_until.get(&sender).copied().unwrap_or(time));
+        let end = start + self.transmit_time(msg.len());
+        self.busy_until.insert(sender, end);
+
+        let mut new = Transmission {
+            sender,
+            start,
+            end,
+            msg,
+            collided: false,
+        };
+        for other in &mut self.transmissions {
+            if other.sender != sender && other.overlaps(&new) {
+                log!(
+                    "collision between frames from {} and {} at {}",
+                    other.sender,
+                    sender,
+                    start
+                );
+                if !other.collided {
+                    self.collisions += 1;
+                }
+                other.collided = true;
+                new.collided = true;
+            }
+        }
+        self.transmissions.push(new);
+    }
+}
+
+impl Node for SharedMedium {
+    fn poll(&mut self, time: Time, incoming: IncomingMsgs) -> OutgoingMsgs {
+        for (sender, message) in incoming {
+            assert!(
+                self.ends.contains(&sender),
+                "Tried to send from a machine not attached to the medium"
+            );
+            self.transmit(time, sender, message);
+        }
+
+        // Deliver frames that have finished arriving
+        let delay = self.delay;
+        let (done, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.transmissions)
+            .into_iter()
+            .partition(|t| t.end + delay <= time);
+        self.transmissions = pending;
+
+        let mut result = Vec::new();
+        for t in done {
+            if t.collided {
+                continue;
+            }
+            for &dest in &self.ends {
+                if dest != t.sender && !self.separated(t.sender, dest) {
+                    result.push((dest, t.msg.clone()));
+                }
+            }
+        }
+        result
+    }
+
+    fn poll_at(&mut self) -> Option<Time> {
+        self.transmissions.iter().map(|t| t.end + self.delay).min()
+    }
+
+    fn is_link(&self) -> bool {
+        true
+    }
+}
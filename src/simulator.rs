@@ -1,7 +1,16 @@
 use core::str;
-use std::fmt::Write;
+use std::{
+    any::Any,
+    cmp::Ordering,
+    collections::HashMap,
+    fmt::{self, Write},
+    panic::{self, AssertUnwindSafe},
+};
+
+use smoltcp::wire::IpEndpoint;
 
 use crate::log;
+use crate::packet::{tcp_flow, tcp_segment};
 
 pub type Msg = Vec<u8>;
 
@@ -20,7 +29,7 @@ pub type IncomingMsgs = Vec<(Index, Msg)>;
 /// Using this trait can be a little awkward, since it requires your
 /// entire node to be a state machine. For ease of use, consider using the
 /// [`SchedulerNode`] trait.
-pub trait Node {
+pub trait Node: AsAny {
     /// Tells the node the current time, and messages it has received,
     /// so it can act accordingly.
     ///
@@ -29,13 +38,18 @@ pub trait Node {
     /// * `time` - the time that the node is being polled
     ///
     /// * `incoming` - the incoming messages. Each pair contains the
-    /// index of the machine that sent it, and the message itself.
-    /// May be empty.
+    ///   index of the machine that sent it, and the message itself.
+    ///   May be empty.
     ///
     /// # Returns
     /// The node returns a vec of messages it wants to send out, along with
     /// the indices of each node that will receive the message.
     ///
+    /// A node can send messages to its own index, e.g. to post events to
+    /// itself. They go in its own mailbox like any other message, so it is
+    /// polled again at the same time to receive them. To do something later
+    /// instead, return that time from [`poll_at`](Node::poll_at).
+    ///
     /// # Panics
     ///
     /// Nodes are allowed to panic if the time passed in
@@ -45,51 +59,698 @@ pub trait Node {
 
     /// Returns the next time this machine should be polled.
     fn poll_at(&mut self) -> Option<Time>;
+
+    /// Whether this node is a link (like a [`Wire`](crate::wire::Wire))
+    /// that just carries messages between other nodes.
+    /// Messages sent to links aren't counted as delivered by
+    /// [`run_sim_until_packets`].
+    fn is_link(&self) -> bool {
+        false
+    }
+
+    /// The time of the next event scheduled on this node, if it schedules
+    /// events. Nodes that do should return it, so their polls for events
+    /// are counted as such.
+    fn next_event_time(&self) -> Option<Time> {
+        None
+    }
+
+    /// Why the node wants to be polled at the time [`poll_at`](Node::poll_at)
+    /// returns, for [`SimStats::polls`]. By default it's an event if the
+    /// next one is due then (see [`next_event_time`](Node::next_event_time)),
+    /// and a timer otherwise.
+    fn wake_reason(&mut self) -> WakeReason {
+        let next_event = self.next_event_time();
+        if next_event.is_some() && next_event == self.poll_at() {
+            WakeReason::Event
+        } else {
+            WakeReason::Timer
+        }
+    }
 }
 
-/// Runs a simulation of the machines until the given time has passed.
-pub fn run_sim_until(nodes: &mut [&mut dyn Node], end_time: Time) {
-    // The current time.
-    let mut time = match earliest_poll_time(nodes) {
-        Some((_index, time)) => time,
-        None => return,
-    };
+/// Why a node was polled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WakeReason {
+    /// Messages were waiting in its mailbox.
+    Mail,
+    /// It asked to be polled then, e.g. for a TCP timer or a message
+    /// finishing its trip across a wire.
+    Timer,
+    /// It asked to be polled for an event scheduled on it,
+    /// like one from [`ElvOs::add_event`](crate::tcp_machine::ElvOs::add_event).
+    Event,
+}
+
+/// How many times a node was polled, by [`WakeReason`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NodePolls {
+    pub mail: u64,
+    pub timer: u64,
+    pub event: u64,
+}
+
+impl NodePolls {
+    /// The total number of polls.
+    pub fn total(&self) -> u64 {
+        self.mail + self.timer + self.event
+    }
+}
+
+/// Lets a node be turned back into its concrete type.
+/// This is implemented automatically for every type.
+pub trait AsAny {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl<'a> dyn Node + 'a {
+    /// Returns the node as a `T`, or `None` if it isn't one.
+    pub fn downcast_ref<T: Node + 'static>(&self) -> Option<&T> {
+        self.as_any().downcast_ref()
+    }
+
+    /// Returns the node as a `T`, or `None` if it isn't one.
+    pub fn downcast_mut<T: Node + 'static>(&mut self) -> Option<&mut T> {
+        self.as_any_mut().downcast_mut()
+    }
+}
+
+/// An event is just a function and the time it gets called on a node `N`.
+/// Ordered so that the earliest events come first in Rust's BinaryHeap.
+pub(crate) struct Event<N>(pub Time, pub Box<dyn FnOnce(&mut N)>);
+
+impl<N> PartialEq for Event<N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq(&other.0)
+    }
+}
+
+impl<N> Eq for Event<N> {}
+
+impl<N> PartialOrd for Event<N> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<N> Ord for Event<N> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0).reverse()
+    }
+}
+
+/// A running simulation.
+///
+/// Each node is identified by its index, which is its position in the list
+/// of nodes. Nodes can be added while the simulation is running, but never
+/// removed, so an index always refers to the same node.
+pub struct Simulation<'a> {
+    nodes: Vec<&'a mut dyn Node>,
+    /// The messages each node needs to receive.
+    mailboxes: Vec<IncomingMsgs>,
+    /// The current time.
+    time: Time,
+    /// Called with every message sent.
+    observer: Option<Box<Observer<'a>>>,
+    /// Messages delivered to nodes that aren't links,
+    /// if they are being recorded.
+    deliveries: Option<Vec<Delivery>>,
+    /// The number of times a node has been polled.
+    steps: u64,
+    /// The number of messages delivered to nodes that aren't links.
+    packets_delivered: u64,
+    /// The number of bytes sent by nodes that aren't links.
+    wire_bytes: u64,
+    /// The number of TCP payload bytes delivered, not counting duplicates.
+    goodput_bytes: u64,
+    /// How many times each node was polled, and why.
+    polls: Vec<NodePolls>,
+    /// What has been delivered of each TCP flow, by its endpoints.
+    flows: HashMap<(IpEndpoint, IpEndpoint), FlowProgress>,
+    /// How often to report progress, and the function to report it to.
+    progress: Option<(Time, Box<Progress<'a>>)>,
+    /// The next time progress should be reported.
+    next_progress: Time,
+    mailbox_order: MailboxOrder,
+    /// Whether to catch panics from nodes' `poll`.
+    catch_panics: bool,
+    /// The panic that was caught, if any.
+    panicked: Option<NodePanic>,
+}
+
+/// The order a node receives messages that are sent to it at the same time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MailboxOrder {
+    /// A node is polled as soon as a message is sent to it, so messages from
+    /// different senders arrive in separate polls, in the order the senders
+    /// were polled. Nodes due at the same time are polled lowest index first.
+    #[default]
+    Arrival,
+    /// Every node due at the current time is polled before any messages are
+    /// delivered. Then each node gets the messages sent to it by those nodes
+    /// in one poll, sorted by the sender's index. Messages from the same sender
+    /// stay in the order they were sent.
+    ///
+    /// Messages sent in response to a delivery (like a zero-delay wire
+    /// passing one on) arrive in a later poll at the same time.
+    SourceIndex,
+}
+
+/// A message that was delivered to a node.
+#[derive(Clone, Debug)]
+pub struct Delivery {
+    pub time: Time,
+    pub from: Index,
+    pub to: Index,
+    pub msg: Msg,
+}
+
+impl fmt::Display for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at {} from {} to {}: ", self.time, self.from, self.to)?;
+        write!(f, "{}", msg_to_str(&self.msg).trim_end())
+    }
+}
+
+/// Formats a trace of deliveries as text, one delivery after another,
+/// each decoded like [`Delivery`]'s `Display`.
+///
+/// The same deliveries always give the same text, so traces of
+/// a deterministic scenario can be compared between runs.
+pub fn trace_to_string(trace: &[Delivery]) -> String {
+    let mut result = String::new();
+    for delivery in trace {
+        let _ = writeln!(result, "{delivery}");
+    }
+    result
+}
+
+/// A node panicked while being polled, or handling what it sent did
+/// (e.g. the observer). See [`Simulation::try_run_until`].
+#[derive(Clone, Debug)]
+pub struct NodePanic {
+    /// The index of the node that panicked.
+    pub node: Index,
+    /// The time it was polled at.
+    pub time: Time,
+    /// What the panic said.
+    pub message: String,
+    /// The messages the node was given in that poll.
+    pub incoming: Vec<Delivery>,
+}
+
+impl fmt::Display for NodePanic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "node {} panicked at {}: {}",
+            self.node, self.time, self.message
+        )?;
+        for delivery in &self.incoming {
+            write!(f, "\n  received {delivery}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for NodePanic {}
+
+/// Sees a message as it is sent, along with the time,
+/// the index of the sender, and the index of the destination.
+pub type Observer<'a> = dyn FnMut(Time, Index, Index, &Msg) + 'a;
+
+/// Told the current time as a simulation runs.
+pub type Progress<'a> = dyn FnMut(Time) + 'a;
+
+impl<'a> Simulation<'a> {
+    pub fn new(mut nodes: Vec<&'a mut dyn Node>) -> Simulation<'a> {
+        let time = match earliest_poll_time(&mut nodes) {
+            Some((_index, time)) => time,
+            None => 0,
+        };
+        Simulation {
+            mailboxes: vec![IncomingMsgs::new(); nodes.len()],
+            polls: vec![NodePolls::default(); nodes.len()],
+            nodes,
+            time,
+            observer: None,
+            deliveries: None,
+            steps: 0,
+            packets_delivered: 0,
+            wire_bytes: 0,
+            goodput_bytes: 0,
+            flows: HashMap::new(),
+            progress: None,
+            next_progress: 0,
+            mailbox_order: MailboxOrder::Arrival,
+            catch_panics: false,
+            panicked: None,
+        }
+    }
+
+    /// Adds a node to the simulation, and returns its index.
+    ///
+    /// The node is polled right away at the current time, so it starts out
+    /// in sync with the rest of the simulation. Any node it sends to must
+    /// already be in the simulation, so add a wire before the host that uses it.
+    ///
+    /// Nodes that are already in the simulation don't learn about the new
+    /// one: a host keeps sending to its one receiver, and a [`Wire`] keeps
+    /// its two ends. To reach existing hosts, a new host has to join a link
+    /// they already use, like a [`SharedMedium`] it has been
+    /// [attached](crate::shared_medium::SharedMedium::attach) to.
+    ///
+    /// [`Wire`]: crate::wire::Wire
+    /// [`SharedMedium`]: crate::shared_medium::SharedMedium
+    pub fn add_node(&mut self, node: &'a mut dyn Node) -> Index {
+        let index = self.nodes.len();
+        self.nodes.push(node);
+        self.mailboxes.push(IncomingMsgs::new());
+        self.polls.push(NodePolls::default());
+        self.poll_node(index, self.time);
+        index
+    }
+
+    /// Sets a function that sees every message sent between nodes,
+    /// e.g. to check the flags of segments crossing a wire.
+    /// See the [`packet`](crate::packet) module for decoding them.
+    pub fn set_observer(&mut self, observer: impl FnMut(Time, Index, Index, &Msg) + 'a) {
+        self.observer = Some(Box::new(observer));
+    }
+
+    /// Calls `progress` with the current time whenever another `interval`
+    /// of simulated time has passed, so long runs can report how far along
+    /// they are. It is called at the first poll after each interval ends,
+    /// so intervals where nothing happens are skipped.
+    pub fn set_progress(&mut self, interval: Time, progress: impl FnMut(Time) + 'a) {
+        assert!(interval > 0, "progress interval must be positive");
+        self.progress = Some((interval, Box::new(progress)));
+        self.next_progress = (self.time / interval + 1) * interval;
+    }
+
+    /// Sets the order nodes receive messages sent to them at the same time.
+    /// See [`MailboxOrder`].
+    pub fn set_mailbox_order(&mut self, order: MailboxOrder) {
+        self.mailbox_order = order;
+    }
+
+    /// The time of the last poll.
+    pub fn current_time(&self) -> Time {
+        self.time
+    }
+
+    /// The number of times a node has been polled.
+    pub fn steps_taken(&self) -> u64 {
+        self.steps
+    }
+
+    /// The number of messages delivered to nodes that aren't links.
+    pub fn packets_delivered(&self) -> u64 {
+        self.packets_delivered
+    }
 
-    // the messages each machine needs to receive
-    let mut mailboxes: Vec<IncomingMsgs> = vec![IncomingMsgs::new(); nodes.len()];
+    /// Counts of what the simulation has done so far.
+    pub fn stats(&self) -> SimStats {
+        SimStats {
+            time: self.time,
+            steps: self.steps,
+            packets_delivered: self.packets_delivered,
+            wire_bytes: self.wire_bytes,
+            goodput_bytes: self.goodput_bytes,
+            polls: self.polls.clone(),
+        }
+    }
+
+    /// Returns the node with the given index.
+    pub fn node(&mut self, index: Index) -> &mut dyn Node {
+        &mut *self.nodes[index]
+    }
+
+    /// The next time the simulation will poll a node: the current time if a
+    /// message is waiting to be delivered, otherwise the earliest time a node
+    /// asked to be polled at. `None` if nothing will ever happen again.
+    pub fn next_event_time(&mut self) -> Option<Time> {
+        let (_index, time) = machine_to_poll(
+            &mut self.nodes,
+            &self.mailboxes,
+            self.time,
+            self.mailbox_order,
+        )?;
+        Some(time)
+    }
+
+    /// Polls the next node that needs it.
+    ///
+    /// Returns the index of the node and the time it was polled at,
+    /// or `None` if no node needs to be polled.
+    pub fn step(&mut self) -> Option<(Index, Time)> {
+        let (i, time) = machine_to_poll(
+            &mut self.nodes,
+            &self.mailboxes,
+            self.time,
+            self.mailbox_order,
+        )?;
+        self.poll_node(i, time);
+        Some((i, time))
+    }
+
+    /// Runs the simulation until the given time has passed.
+    /// Afterwards, the current time is `end_time`.
+    pub fn run_until(&mut self, end_time: Time) {
+        self.run_until_predicate(end_time, |_| false);
+    }
 
-    while let Some((i, t)) = machine_to_poll(nodes, &mailboxes, time) {
-        time = t;
-        log!("{i} polled at {time}");
-        if time > end_time {
-            break;
+    /// Runs the simulation until `predicate` returns true,
+    /// or the given time has passed.
+    ///
+    /// The predicate is checked after every poll. It can look at the state of
+    /// specific nodes by downcasting them, e.g. `nodes[0].downcast_ref::<ElvOs>()`.
+    ///
+    /// Returns whether the predicate returned true.
+    pub fn run_until_predicate(
+        &mut self,
+        end_time: Time,
+        mut predicate: impl FnMut(&[&mut dyn Node]) -> bool,
+    ) -> bool {
+        while let Some((i, t)) = machine_to_poll(
+            &mut self.nodes,
+            &self.mailboxes,
+            self.time,
+            self.mailbox_order,
+        ) {
+            log!("{i} polled at {t}");
+            if t > end_time {
+                break;
+            }
+            self.poll_node(i, t);
+            if self.panicked.is_some() {
+                return false;
+            }
+
+            if predicate(&self.nodes) {
+                return true;
+            }
         }
+        // nothing else happens before the end
+        self.time = Time::max(self.time, end_time);
+        false
+    }
 
-        let outgoing = nodes[i].poll(time, take_all(&mut mailboxes[i]));
+    /// Like [`run_until`](Simulation::run_until), but if a node panics
+    /// while being polled, stops and returns which node it was, when,
+    /// and what it was given. Panics while logging and delivering what the
+    /// node sent, including in the observer, are blamed on that node.
+    /// The node may be left in a broken state, so the simulation
+    /// shouldn't be run any more after that.
+    pub fn try_run_until(&mut self, end_time: Time) -> Result<(), NodePanic> {
+        self.catch_panics = true;
+        self.run_until(end_time);
+        self.catch_panics = false;
+        match self.panicked.take() {
+            Some(node_panic) => Err(node_panic),
+            None => Ok(()),
+        }
+    }
 
+    /// Runs the simulation until `n` messages have been delivered to nodes
+    /// that aren't links, or until no node needs to be polled.
+    /// Returns those messages, in the order they were delivered.
+    ///
+    /// Messages are counted when they reach the destination's mailbox,
+    /// so if a link delivers several at once, the ones after the `n`th
+    /// stay in the mailbox until the simulation runs again.
+    pub fn run_until_packets(&mut self, n: usize) -> Vec<Delivery> {
+        self.deliveries = Some(Vec::new());
+        while self.deliveries.as_ref().unwrap().len() < n {
+            if self.step().is_none() {
+                break;
+            }
+        }
+        let mut deliveries = self.deliveries.take().unwrap();
+        deliveries.truncate(n);
+        deliveries
+    }
+
+    /// Polls a node, and puts the messages it sends in their mailboxes.
+    fn poll_node(&mut self, i: Index, time: Time) {
+        self.time = time;
+        self.steps += 1;
+        if let Some((interval, progress)) = &mut self.progress {
+            if time >= self.next_progress {
+                progress(time);
+                self.next_progress = (time / *interval + 1) * *interval;
+            }
+        }
+        if self.mailbox_order == MailboxOrder::SourceIndex {
+            self.mailboxes[i].sort_by_key(|(sender, _msg)| *sender);
+        }
+        let reason = if self.mailboxes[i].is_empty() {
+            self.nodes[i].wake_reason()
+        } else {
+            WakeReason::Mail
+        };
+        let polls = &mut self.polls[i];
+        match reason {
+            WakeReason::Mail => polls.mail += 1,
+            WakeReason::Timer => polls.timer += 1,
+            WakeReason::Event => polls.event += 1,
+        }
+        let incoming = take_all(&mut self.mailboxes[i]);
+        let incoming_copy = self.catch_panics.then(|| {
+            Vec::from_iter(incoming.iter().map(|(from, msg)| Delivery {
+                time,
+                from: *from,
+                to: i,
+                msg: msg.clone(),
+            }))
+        });
+        let poll = || {
+            let outgoing = self.nodes[i].poll(time, incoming);
+            self.send(i, time, outgoing);
+        };
+        let Some(incoming_copy) = incoming_copy else {
+            poll();
+            return;
+        };
+        // what the node sent is handled inside too, since the observer
+        // or a bad destination can panic as well
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(poll)) {
+            let message = match payload.downcast::<String>() {
+                Ok(message) => *message,
+                Err(payload) => match payload.downcast::<&str>() {
+                    Ok(message) => message.to_string(),
+                    Err(_) => "unknown panic".to_string(),
+                },
+            };
+            self.panicked = Some(NodePanic {
+                node: i,
+                time,
+                message,
+                incoming: incoming_copy,
+            });
+        }
+    }
+
+    /// Logs the messages node `i` sent, and puts them in their mailboxes.
+    fn send(&mut self, i: Index, time: Time, outgoing: OutgoingMsgs) {
         // prints out the packets sent
         for (dest, msg) in &outgoing {
-            log!("packet from {i} to {dest}: {}", packet_to_str(msg).unwrap());
+            log!("packet from {i} to {dest}: {}", msg_to_str(msg));
         }
 
         // deliver messages to mailboxes
+        let from_link = self.nodes[i].is_link();
         for (destination, msg) in outgoing {
-            mailboxes[destination].push((i, msg));
+            assert!(
+                destination < self.nodes.len(),
+                "node {i} sent a message to node {destination}, which isn't in the simulation"
+            );
+            if let Some(observer) = &mut self.observer {
+                observer(time, i, destination, &msg);
+            }
+            if !from_link {
+                self.wire_bytes += msg.len() as u64;
+            }
+            if !self.nodes[destination].is_link() {
+                self.packets_delivered += 1;
+                self.count_goodput(&msg);
+                if let Some(deliveries) = &mut self.deliveries {
+                    deliveries.push(Delivery {
+                        time,
+                        from: i,
+                        to: destination,
+                        msg: msg.clone(),
+                    });
+                }
+            }
+            self.mailboxes[destination].push((i, msg));
+        }
+    }
+
+    /// Adds the TCP payload bytes in a delivered message that haven't
+    /// been delivered before to the goodput.
+    fn count_goodput(&mut self, msg: &Msg) {
+        let (Some(flow), Some(tcp)) = (tcp_flow(msg), tcp_segment(msg)) else {
+            return;
+        };
+        // the SYN takes up the first sequence number
+        let data_start = (tcp.seq_number().0 as u32).wrapping_add(u32::from(tcp.syn()));
+        // a SYN starts a new connection, even if the endpoints were used before
+        if tcp.syn() {
+            self.flows.remove(&flow);
+        }
+        let progress = self.flows.entry(flow).or_insert(FlowProgress {
+            start: data_start,
+            delivered: Vec::new(),
+        });
+        self.goodput_bytes += progress.add(data_start, tcp.payload().len());
+    }
+}
+
+/// Statistics about a simulation, from [`Simulation::stats`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SimStats {
+    /// The time of the last poll.
+    pub time: Time,
+    /// The number of times a node has been polled.
+    pub steps: u64,
+    /// The number of messages delivered to nodes that aren't links.
+    pub packets_delivered: u64,
+    /// The total size of the messages sent by nodes that aren't links,
+    /// including headers, ARP, ACKs, retransmissions,
+    /// and messages that were lost on the way.
+    pub wire_bytes: u64,
+    /// The number of TCP payload bytes delivered to nodes that aren't links,
+    /// counting each byte of a connection once.
+    pub goodput_bytes: u64,
+    /// How many times each node was polled, and why, by index.
+    pub polls: Vec<NodePolls>,
+}
+
+impl SimStats {
+    /// The fraction of the bytes sent that were useful data, `goodput_bytes`
+    /// over `wire_bytes`. Returns 0 if nothing was sent.
+    pub fn efficiency(&self) -> f64 {
+        if self.wire_bytes == 0 {
+            0.0
+        } else {
+            self.goodput_bytes as f64 / self.wire_bytes as f64
+        }
+    }
+}
+
+/// Which bytes of a TCP flow have been delivered.
+struct FlowProgress {
+    /// The sequence number of the first byte seen.
+    start: u32,
+    /// The ranges of offsets from `start` that have been delivered,
+    /// sorted and not overlapping.
+    delivered: Vec<(u64, u64)>,
+}
+
+impl FlowProgress {
+    /// Marks the bytes starting at sequence number `seq` as delivered,
+    /// and returns how many of them weren't delivered before.
+    fn add(&mut self, seq: u32, len: usize) -> u64 {
+        let offset = seq.wrapping_sub(self.start);
+        if len == 0 || offset > u32::MAX / 2 {
+            // nothing, or data from before the first byte seen
+            return 0;
+        }
+        let (start, end) = (u64::from(offset), u64::from(offset) + len as u64);
+        let mut new = end - start;
+        for &(s, e) in &self.delivered {
+            new -= u64::min(e, end).saturating_sub(u64::max(s, start));
         }
+
+        self.delivered.push((start, end));
+        self.delivered.sort();
+        let mut merged: Vec<(u64, u64)> = Vec::new();
+        for &(s, e) in &self.delivered {
+            match merged.last_mut() {
+                Some(last) if s <= last.1 => last.1 = u64::max(last.1, e),
+                _ => merged.push((s, e)),
+            }
+        }
+        self.delivered = merged;
+        new
     }
 }
 
+/// Runs a simulation of the machines until the given time has passed.
+pub fn run_sim_until(nodes: &mut [&mut dyn Node], end_time: Time) {
+    run_sim_until_predicate(nodes, end_time, |_| false);
+}
+
+/// Like [`run_sim_until`], but stops if a node panics, and says which one.
+///
+/// See [`Simulation::try_run_until`].
+pub fn try_run_sim_until(nodes: &mut [&mut dyn Node], end_time: Time) -> Result<(), NodePanic> {
+    let nodes = Vec::from_iter(nodes.iter_mut().map(|node| &mut **node as &mut dyn Node));
+    Simulation::new(nodes).try_run_until(end_time)
+}
+
+/// Like [`run_sim_until`], but calls `progress` with the current time
+/// whenever another `interval` of simulated time has passed.
+pub fn run_sim_until_with_progress(
+    nodes: &mut [&mut dyn Node],
+    end_time: Time,
+    interval: Time,
+    progress: impl FnMut(Time),
+) {
+    let nodes = Vec::from_iter(nodes.iter_mut().map(|node| &mut **node as &mut dyn Node));
+    let mut sim = Simulation::new(nodes);
+    sim.set_progress(interval, progress);
+    sim.run_until(end_time);
+}
+
+/// Runs a simulation of the machines until `predicate` returns true,
+/// or the given time has passed.
+///
+/// See [`Simulation::run_until_predicate`].
+pub fn run_sim_until_predicate(
+    nodes: &mut [&mut dyn Node],
+    end_time: Time,
+    predicate: impl FnMut(&[&mut dyn Node]) -> bool,
+) -> bool {
+    let nodes = Vec::from_iter(nodes.iter_mut().map(|node| &mut **node as &mut dyn Node));
+    Simulation::new(nodes).run_until_predicate(end_time, predicate)
+}
+
 fn machine_to_poll(
     nodes: &mut [&mut dyn Node],
     mailboxes: &[IncomingMsgs],
     current_time: Time,
+    order: MailboxOrder,
 ) -> Option<(Index, Time)> {
-    for node in nodes.iter_mut() {
+    let mut due_now = Vec::new();
+    for (i, node) in nodes.iter_mut().enumerate() {
         if let Some(time) = node.poll_at() {
             assert!(
                 time >= current_time,
                 "machines should not be polled in the past"
             );
+            if time == current_time {
+                due_now.push(i);
+            }
+        }
+    }
+
+    // messages wait until everything else happening now has been sent
+    if order == MailboxOrder::SourceIndex {
+        if let Some(&i) = due_now.iter().find(|&&i| mailboxes[i].is_empty()) {
+            return Some((i, current_time));
         }
     }
 
@@ -104,6 +765,14 @@ fn machine_to_poll(
     earliest_poll_time(nodes)
 }
 
+/// Runs a simulation of the machines until `n` messages have been delivered.
+///
+/// See [`Simulation::run_until_packets`].
+pub fn run_sim_until_packets(nodes: &mut [&mut dyn Node], n: usize) -> Vec<Delivery> {
+    let nodes = Vec::from_iter(nodes.iter_mut().map(|node| &mut **node as &mut dyn Node));
+    Simulation::new(nodes).run_until_packets(n)
+}
+
 /// Goes through the nodes and returns the index of the
 /// one with the earilest poll time.
 pub fn earliest_poll_time(nodes: &mut [&mut dyn Node]) -> Option<(Index, Time)> {
@@ -115,10 +784,8 @@ pub fn earliest_poll_time(nodes: &mut [&mut dyn Node]) -> Option<(Index, Time)>
         match (i_poll_at, earliest) {
             // both the earliest machine and the current machine
             // have a set poll time
-            (Some(i_time), Some((_index, early_time))) => {
-                if i_time < early_time {
-                    earliest = Some((i, i_time));
-                }
+            (Some(i_time), Some((_index, early_time))) if i_time < early_time => {
+                earliest = Some((i, i_time));
             }
             // the earliest time is not yet set
             (Some(i_time), None) => {
@@ -137,6 +804,12 @@ fn take_all(v: &mut IncomingMsgs) -> IncomingMsgs {
     result
 }
 
+/// Describes a message: the packet in it, or just its length if it
+/// isn't an Ethernet frame, since nodes can send each other anything.
+fn msg_to_str(msg: &[u8]) -> String {
+    packet_to_str(msg).unwrap_or_else(|_| format!("{} bytes", msg.len()))
+}
+
 /// Interprets a bunch of bytes as an ethernet-ip-tcp packet
 /// and turns them into a string
 fn packet_to_str(packet: &[u8]) -> Result<String, smoltcp::wire::Error> {
@@ -148,9 +821,12 @@ fn packet_to_str(packet: &[u8]) -> Result<String, smoltcp::wire::Error> {
     if eth.ethertype() == smoltcp::wire::EthernetProtocol::Ipv4 {
         let ip = Ipv4Packet::new_checked(eth.payload())?;
         let _ = writeln!(result, "\t{ip}");
+        let _ = writeln!(result, "\tIPv4 checksum {}", validity(ip.verify_checksum()));
         if ip.next_header() == smoltcp::wire::IpProtocol::Tcp {
             let tcp = TcpPacket::new_checked(ip.payload())?;
             let _ = writeln!(result, "\t{tcp}");
+            let valid = tcp.verify_checksum(&ip.src_addr().into(), &ip.dst_addr().into());
+            let _ = writeln!(result, "\tTCP checksum {}", validity(valid));
 
             let payload = str::from_utf8(tcp.payload());
             let _ = writeln!(result, "\tPayload: {payload:?}");
@@ -159,3 +835,12 @@ fn packet_to_str(packet: &[u8]) -> Result<String, smoltcp::wire::Error> {
 
     Ok(result)
 }
+
+/// How `packet_to_str` describes a checksum.
+fn validity(valid: bool) -> &'static str {
+    if valid {
+        "valid"
+    } else {
+        "INVALID"
+    }
+}
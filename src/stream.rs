@@ -0,0 +1,233 @@
+//! A pair of nodes that send and check a seeded pseudorandom byte stream,
+//! to make sure TCP delivers every byte in order no matter what the
+//! wires in between do.
+//!
+//! Like the [`http`](crate::http) nodes, both wrap an [`ElvOs`] and run
+//! after every poll.
+
+use smoltcp::{
+    iface::SocketHandle,
+    socket::tcp::State,
+    wire::{IpEndpoint, IpListenEndpoint},
+};
+
+use crate::rng::Rng;
+use crate::simulator::{IncomingMsgs, Node, OutgoingMsgs, Time};
+use crate::tcp_machine::{ElvError, ElvOs};
+
+/// The bytes of a stream, generated from a seed.
+struct ByteStream {
+    rng: Rng,
+    /// Bytes from the last number that haven't been used yet.
+    leftover: Vec<u8>,
+}
+
+impl ByteStream {
+    fn new(seed: u64) -> ByteStream {
+        ByteStream {
+            rng: Rng::new(seed),
+            leftover: Vec::new(),
+        }
+    }
+
+    /// Returns the next `len` bytes of the stream.
+    fn take(&mut self, len: usize) -> Vec<u8> {
+        let mut result = Vec::with_capacity(len);
+        while result.len() < len {
+            if self.leftover.is_empty() {
+                self.leftover = self.rng.next_u64().to_le_bytes().to_vec();
+            }
+            let num = usize::min(len - result.len(), self.leftover.len());
+            result.extend(self.leftover.drain(..num));
+        }
+        result
+    }
+}
+
+/// Connects to a [`StreamVerifier`], sends it `len` bytes of the
+/// stream with the given seed, then closes the connection.
+pub struct StreamSender {
+    os: ElvOs,
+    sock: SocketHandle,
+    stream: ByteStream,
+    /// Bytes taken from the stream that haven't been sent yet.
+    pending: Vec<u8>,
+    /// The number of bytes not taken from the stream yet.
+    remaining: usize,
+    closed: bool,
+}
+
+impl StreamSender {
+    pub fn new(
+        mut os: ElvOs,
+        local: impl Into<IpListenEndpoint>,
+        remote: impl Into<IpEndpoint>,
+        seed: u64,
+        len: usize,
+    ) -> Result<StreamSender, ElvError> {
+        let sock = os.socket();
+        StreamSender::with_socket(os, sock, local, remote, seed, len)
+    }
+
+    /// Like [`new`](StreamSender::new), but sends from a socket already
+    /// made on `os`, e.g. one from [`ElvOs::socket_with_buffers`].
+    pub fn with_socket(
+        mut os: ElvOs,
+        sock: SocketHandle,
+        local: impl Into<IpListenEndpoint>,
+        remote: impl Into<IpEndpoint>,
+        seed: u64,
+        len: usize,
+    ) -> Result<StreamSender, ElvError> {
+        os.connect(sock, local, remote)?;
+        Ok(StreamSender {
+            os,
+            sock,
+            stream: ByteStream::new(seed),
+            pending: Vec::new(),
+            remaining: len,
+            closed: false,
+        })
+    }
+
+    pub fn os(&mut self) -> &mut ElvOs {
+        &mut self.os
+    }
+
+    pub fn sock(&self) -> SocketHandle {
+        self.sock
+    }
+
+    /// Whether every byte has been handed to the socket.
+    pub fn done(&self) -> bool {
+        self.closed
+    }
+
+    fn run_app(&mut self) {
+        if self.closed || !self.os.may_send(self.sock) {
+            return;
+        }
+        loop {
+            if self.pending.is_empty() {
+                let len = usize::min(self.remaining, 1024);
+                self.pending = self.stream.take(len);
+                self.remaining -= len;
+            }
+            if self.pending.is_empty() {
+                self.closed = true;
+                self.os.close(self.sock);
+                return;
+            }
+            match self.os.send(self.sock, &self.pending) {
+                Ok(sent) => {
+                    self.pending.drain(..sent);
+                }
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+impl Node for StreamSender {
+    fn poll(&mut self, time: Time, incoming: IncomingMsgs) -> OutgoingMsgs {
+        let outgoing = self.os.poll(time, incoming);
+        self.run_app();
+        outgoing
+    }
+
+    fn poll_at(&mut self) -> Option<Time> {
+        self.os.poll_at()
+    }
+}
+
+/// Accepts a connection from a [`StreamSender`], and checks every byte
+/// against the stream with the given seed as it arrives.
+///
+/// Panics as soon as a byte doesn't match, saying where it was.
+pub struct StreamVerifier {
+    os: ElvOs,
+    sock: SocketHandle,
+    stream: ByteStream,
+    /// The number of bytes received (and checked) so far.
+    received: usize,
+}
+
+impl StreamVerifier {
+    pub fn new(
+        mut os: ElvOs,
+        endpoint: impl Into<IpListenEndpoint>,
+        seed: u64,
+    ) -> Result<StreamVerifier, ElvError> {
+        let sock = os.socket();
+        StreamVerifier::with_socket(os, sock, endpoint, seed)
+    }
+
+    /// Like [`new`](StreamVerifier::new), but listens on a socket already
+    /// made on `os`, e.g. one from [`ElvOs::socket_with_buffers`].
+    pub fn with_socket(
+        mut os: ElvOs,
+        sock: SocketHandle,
+        endpoint: impl Into<IpListenEndpoint>,
+        seed: u64,
+    ) -> Result<StreamVerifier, ElvError> {
+        os.listen(sock, endpoint)?;
+        Ok(StreamVerifier {
+            os,
+            sock,
+            stream: ByteStream::new(seed),
+            received: 0,
+        })
+    }
+
+    pub fn os(&mut self) -> &mut ElvOs {
+        &mut self.os
+    }
+
+    pub fn sock(&self) -> SocketHandle {
+        self.sock
+    }
+
+    /// The number of bytes received so far. They were all correct.
+    pub fn received(&self) -> usize {
+        self.received
+    }
+
+    /// Whether the sender has closed the connection,
+    /// meaning the whole stream has arrived.
+    ///
+    /// The verifier never closes its side, so that leaves it waiting in
+    /// `CloseWait`. A connection that was reset isn't finished.
+    pub fn finished(&self) -> bool {
+        self.os.state(self.sock) == State::CloseWait
+    }
+
+    fn run_app(&mut self) {
+        let data = self.os.recv(self.sock);
+        let expected = self.stream.take(data.len());
+        if let Some(i) = data
+            .iter()
+            .zip(&expected)
+            .position(|(got, want)| got != want)
+        {
+            panic!(
+                "stream differs at offset {}: expected {}, got {}",
+                self.received + i,
+                expected[i],
+                data[i]
+            );
+        }
+        self.received += data.len();
+    }
+}
+
+impl Node for StreamVerifier {
+    fn poll(&mut self, time: Time, incoming: IncomingMsgs) -> OutgoingMsgs {
+        let outgoing = self.os.poll(time, incoming);
+        self.run_app();
+        outgoing
+    }
+
+    fn poll_at(&mut self) -> Option<Time> {
+        self.os.poll_at()
+    }
+}
@@ -1,18 +1,27 @@
 use smoltcp::{
     iface::{Interface, SocketHandle, SocketSet},
     phy::{Device, RxToken, TxToken},
-    socket::{tcp, AnySocket},
+    socket::{dhcpv4, tcp, AnySocket},
     storage::RingBuffer,
-    time::Instant,
+    time::{Duration, Instant},
     wire::{EthernetAddress, HardwareAddress, IpCidr, IpEndpoint, IpListenEndpoint},
 };
 
-use std::{
-    cmp::Ordering,
-    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
-};
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+
+use crate::log;
+use crate::packet::{reset_reply, tcp_flow, tcp_segment};
+use crate::simulator::{Event, IncomingMsgs, Index, Msg, Node, OutgoingMsgs, Time};
 
-use crate::simulator::{IncomingMsgs, Index, Msg, Node, OutgoingMsgs, Time};
+/// How long (in microseconds) a socket stays in TIME-WAIT after both sides
+/// have closed, before it becomes closed. This is fixed by smoltcp at 10
+/// seconds, instead of the usual 2×MSL, and can't be changed.
+///
+/// smoltcp doesn't count a socket in TIME-WAIT as open, so it can
+/// [`connect`](ElvOs::connect) or [`listen`](ElvOs::listen) again right away,
+/// even on the same 4-tuple. That ends its TIME-WAIT early, instead of
+/// making the reconnect wait like most real stacks would.
+pub const TIME_WAIT: Time = 10_000_000;
 
 #[derive(Default)]
 struct ElvOsDevice {
@@ -94,8 +103,32 @@ impl<'a> TxToken for ElvOsTxToken<'a> {
     }
 }
 
+/// Errors returned by [`ElvOs`] operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElvError {
+    /// The ElvOs has no IP address yet,
+    /// so sockets can't be connected or listen.
+    NoLocalAddress,
+    /// smoltcp refused to connect the socket.
+    Connect(tcp::ConnectError),
+    /// smoltcp refused to make the socket listen.
+    Listen(tcp::ListenError),
+}
+
+impl std::fmt::Display for ElvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ElvError::NoLocalAddress => write!(f, "no local IP address is set"),
+            ElvError::Connect(e) => write!(f, "failed to connect: {e}"),
+            ElvError::Listen(e) => write!(f, "failed to listen: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ElvError {}
+
 pub struct ElvOs {
-    events: BinaryHeap<Event>,
+    events: BinaryHeap<Event<ElvOs>>,
     /// The "device" used to do sending and receiving.
     device: ElvOsDevice,
     interface: Interface,
@@ -103,9 +136,37 @@ pub struct ElvOs {
     /// Extra data associated with each socket
     /// (callbacks)
     socket_data: HashMap<SocketHandle, SocketData>,
+    /// The DHCP socket, and the callback made when it gets an address.
+    dhcp: Option<(SocketHandle, DhcpCallback)>,
     receiver: Index,
     /// The current time on this machine
     time: Time,
+    /// The last time smoltcp asked to be polled at.
+    /// `None` if the sockets or interface may have changed since then.
+    smoltcp_poll_at: Option<Option<Time>>,
+    /// Whether `smoltcp_poll_at` is used, instead of asking smoltcp every time.
+    cache_poll_at: bool,
+    /// Packets that are waiting to be sent because of the egress rate limit.
+    egress_queue: VecDeque<Msg>,
+    /// The most packets that can be sent per millisecond, if limited.
+    egress_rate: Option<usize>,
+    /// The millisecond that `egress_sent` is counting packets for.
+    egress_window: Time,
+    /// The number of packets sent during `egress_window`.
+    egress_sent: usize,
+    /// The most connections that can be accepted per second, if limited.
+    accept_rate: Option<usize>,
+    /// The second that `accepted` is counting connections for.
+    accept_window: Time,
+    /// The number of connections accepted during `accept_window`.
+    accepted: usize,
+    /// The number of SYNs refused because of the accept rate limit.
+    refused_syns: usize,
+    /// How long it takes this ElvOs to start handling a packet.
+    processing_delay: Time,
+    /// Packets that have arrived but aren't being handled yet,
+    /// and the time they can be handled.
+    held: VecDeque<(Time, Msg)>,
 }
 
 impl ElvOs {
@@ -121,8 +182,21 @@ impl ElvOs {
             interface,
             sockets: SocketSet::new(Vec::new()),
             socket_data: HashMap::new(),
+            dhcp: None,
             receiver,
             time,
+            smoltcp_poll_at: None,
+            cache_poll_at: true,
+            egress_queue: VecDeque::new(),
+            egress_rate: None,
+            egress_window: 0,
+            egress_sent: 0,
+            accept_rate: None,
+            accept_window: 0,
+            accepted: 0,
+            refused_syns: 0,
+            processing_delay: 0,
+            held: VecDeque::new(),
         }
     }
 
@@ -135,6 +209,7 @@ impl ElvOs {
     /// Returns a Socket and its associated SocketData.
     /// Panics if the handle is invalid.
     fn get_sock(&mut self, sock: SocketHandle) -> (&mut tcp::Socket<'static>, &mut SocketData) {
+        self.smoltcp_poll_at = None;
         let socket = self.sockets.get_mut(sock);
         let socket_data = self
             .socket_data
@@ -145,23 +220,46 @@ impl ElvOs {
     }
 
     pub fn socket(&mut self) -> SocketHandle {
-        let snd = RingBuffer::new(vec![0; 1500]);
-        let rcv = RingBuffer::new(vec![0; 1500]);
+        self.socket_with_buffers(1500, 1500)
+    }
+
+    /// Creates a socket with receive and send buffers of the given sizes.
+    /// Receive buffers over 64 KiB use TCP window scaling.
+    pub fn socket_with_buffers(&mut self, recv_size: usize, send_size: usize) -> SocketHandle {
+        let snd = RingBuffer::new(vec![0; send_size]);
+        let rcv = RingBuffer::new(vec![0; recv_size]);
+        self.smoltcp_poll_at = None;
         let handle = self.sockets.add(tcp::Socket::new(rcv, snd));
         self.socket_data.insert(handle, SocketData::default());
         handle
     }
 
+    /// Removes a socket, freeing its buffers. Its handle can't be used after
+    /// this. If the socket is still connected, the peer isn't told.
+    pub fn remove_socket(&mut self, sock: SocketHandle) {
+        self.smoltcp_poll_at = None;
+        self.sockets.remove(sock);
+        self.socket_data.remove(&sock);
+    }
+
+    /// Starts connecting the socket to `remote_endpoint`.
+    ///
+    /// If the remote side connects to this socket's endpoint at the same
+    /// time, one side is turned into a listener so that both connect.
+    /// smoltcp can't do a real simultaneous open.
     pub fn connect(
         &mut self,
         sock: SocketHandle,
         local_endpoint: impl Into<IpListenEndpoint>,
         remote_endpoint: impl Into<IpEndpoint>,
-    ) {
-        self.assert_local_set();
+    ) -> Result<(), ElvError> {
+        self.check_local_set()?;
+        self.autotune(sock);
+        self.start_handshake(sock);
+        self.smoltcp_poll_at = None;
         let sock = self.sockets.get_mut::<tcp::Socket>(sock);
         sock.connect(self.interface.context(), remote_endpoint, local_endpoint)
-            .unwrap();
+            .map_err(ElvError::Connect)
     }
 
     /// Called when a connection is created between this sock and another.
@@ -169,54 +267,603 @@ impl ElvOs {
     /// or [`connect`](ElvOs::connect) call.
     pub fn set_connect_callback(&mut self, sock: SocketHandle, cb: fn(&mut ElvOs, SocketHandle)) {
         let sock_data = self.get_sock(sock).1;
-        sock_data.connect = cb;
+        sock_data.callbacks.connect = cb;
     }
 
-    pub fn listen(&mut self, sock: SocketHandle, local_endpoint: impl Into<IpListenEndpoint>) {
-        self.assert_local_set();
+    /// Called when a listening socket gets a SYN and starts a handshake,
+    /// with the endpoint of the peer that sent it. This happens before the
+    /// connect callback, and even if the handshake never finishes.
+    pub fn set_incoming_callback(&mut self, sock: SocketHandle, cb: IncomingCallback) {
+        let sock_data = self.get_sock(sock).1;
+        sock_data.callbacks.incoming = cb;
+    }
+
+    /// Makes the socket listen for incoming connections.
+    ///
+    /// If the endpoint's address is unspecified, the socket accepts connections
+    /// to any of this ElvOs's local addresses. Otherwise it only accepts
+    /// connections to that exact address, so on a host with several addresses
+    /// (see [`add_local_addr`](ElvOs::add_local_addr)) connections to the
+    /// other addresses are refused.
+    pub fn listen(
+        &mut self,
+        sock: SocketHandle,
+        local_endpoint: impl Into<IpListenEndpoint>,
+    ) -> Result<(), ElvError> {
+        self.check_local_set()?;
+        self.autotune(sock);
+        self.start_handshake(sock);
+        let local_endpoint = local_endpoint.into();
+        let (sock, data) = self.get_sock(sock);
+        data.listen_endpoint = Some(local_endpoint);
+        sock.listen(local_endpoint).map_err(ElvError::Listen)
+    }
+
+    /// Stops the socket listening for connections. Other sockets,
+    /// including ones already connected to the same endpoint, aren't affected,
+    /// and new connection attempts to the endpoint are refused (unless
+    /// another socket is listening on it).
+    ///
+    /// Does nothing if the socket isn't listening.
+    pub fn stop_listening(&mut self, sock: SocketHandle) {
         let sock = self.get_sock(sock).0;
-        sock.listen(local_endpoint).unwrap();
+        if sock.state() == tcp::State::Listen {
+            // a listening socket has no peer, so this doesn't send anything
+            sock.abort();
+        }
+    }
+
+    /// Remembers when a connect or listen call was made.
+    fn start_handshake(&mut self, sock: SocketHandle) {
+        let time = self.time;
+        let data = self.get_sock(sock).1;
+        data.handshake_started = Some(time);
+        data.established_at = None;
+    }
+
+    /// How long it took the socket to become established, from the
+    /// [`connect`](ElvOs::connect) or [`listen`](ElvOs::listen) call.
+    /// For a listening socket, this includes the time spent waiting for a client.
+    ///
+    /// Returns `None` if the socket hasn't been established yet.
+    pub fn handshake_time(&mut self, sock: SocketHandle) -> Option<Time> {
+        let data = self.get_sock(sock).1;
+        Some(data.established_at? - data.handshake_started?)
     }
 
+    /// Puts as much of `msg` in the socket's send buffer as fits,
+    /// and returns how many bytes that was.
+    /// This may be less than the whole message if the buffer is nearly full.
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](std::io::ErrorKind::WouldBlock) if the buffer is
+    ///   full, so none of the message could be sent. Try again once the peer
+    ///   has acknowledged some data.
+    /// * [`NotConnected`](std::io::ErrorKind::NotConnected) if the socket
+    ///   can't send, because it isn't connected or this side has closed it.
     pub fn send(&mut self, sock: SocketHandle, msg: &[u8]) -> std::io::Result<usize> {
         use std::io::Error;
         use std::io::ErrorKind;
-        let sock = self.get_sock(sock).0;
-        let mut sent = sock
-            .send_slice(msg)
-            .or(Err(Error::from(ErrorKind::NotConnected)))?;
-        if sent < msg.len() {
-            sent += sock
+        let (sock, data) = self.get_sock(sock);
+        if !sock.may_send() {
+            return Err(Error::from(ErrorKind::NotConnected));
+        }
+        // the send buffer is a ring, so it may take the data in several pieces
+        let mut sent = 0;
+        while sent < msg.len() {
+            let num = sock
                 .send_slice(&msg[sent..])
-                .or(Err(Error::from(ErrorKind::NotConnected)))?
+                .or(Err(Error::from(ErrorKind::NotConnected)))?;
+            if num == 0 {
+                break;
+            }
+            sent += num;
         }
+        if sent == 0 && !msg.is_empty() {
+            return Err(Error::from(ErrorKind::WouldBlock));
+        }
+        data.sending |= sent > 0;
         Ok(sent)
     }
 
+    /// Closes the sending half of the connection.
+    /// Data that was already sent will still be delivered,
+    /// and the socket can receive until the peer closes its half too.
+    pub fn close(&mut self, sock: SocketHandle) {
+        self.get_sock(sock).0.close();
+    }
+
+    /// Called when everything sent on the socket has been acknowledged
+    /// by the peer. This is a good time to close the socket.
+    pub fn set_drained_callback(&mut self, sock: SocketHandle, cb: fn(&mut ElvOs, SocketHandle)) {
+        let sock_data = self.get_sock(sock).1;
+        sock_data.callbacks.drained = cb;
+    }
+
+    /// How much longer the socket will stay in TIME-WAIT, or `None` if it
+    /// isn't in TIME-WAIT. See [`TIME_WAIT`].
+    ///
+    /// This is measured from the last time this ElvOs was polled,
+    /// which is also when the socket is seen entering TIME-WAIT.
+    pub fn time_wait_remaining(&mut self, sock: SocketHandle) -> Option<Time> {
+        let time = self.time;
+        let since = self.get_sock(sock).1.time_wait_since?;
+        Some(Time::max(0, since + TIME_WAIT - time))
+    }
+
+    /// Returns the endpoint of the socket's peer,
+    /// or `None` if it isn't connected or connecting.
+    pub fn remote_endpoint(&self, sock: SocketHandle) -> Option<IpEndpoint> {
+        self.sockets.get::<tcp::Socket>(sock).remote_endpoint()
+    }
+
+    /// Returns the TCP state of the socket.
+    pub fn state(&self, sock: SocketHandle) -> tcp::State {
+        self.sockets.get::<tcp::Socket>(sock).state()
+    }
+
+    /// Returns everything smoltcp knows about the socket, like sequence
+    /// numbers, windows, and timers, for debugging.
+    /// The contents of the send and receive buffers are left out.
+    pub fn socket_debug(&self, sock: SocketHandle) -> String {
+        let debug = format!("{:#?}", self.sockets.get::<tcp::Socket>(sock));
+        // the buffers' storage is printed one byte per line
+        let mut result = String::new();
+        let mut rest = &debug[..];
+        while let Some(start) = rest.find("storage: ") {
+            result.push_str(&rest[..start]);
+            result.push_str("storage: ..");
+            rest = &rest[start..];
+            let end = rest.find(')').map_or(rest.len(), |end| end + 1);
+            rest = &rest[end..];
+        }
+        result.push_str(rest);
+        result
+    }
+
+    /// Returns whether the socket is open, meaning it is listening,
+    /// connecting, or connected. See [`tcp::Socket::is_open`].
+    pub fn is_open(&mut self, sock: SocketHandle) -> bool {
+        self.get_sock(sock).0.is_open()
+    }
+
+    /// Returns whether the socket can still send data, meaning
+    /// this side hasn't closed the connection. The peer may have closed
+    /// its side though. See [`tcp::Socket::may_send`].
+    pub fn may_send(&mut self, sock: SocketHandle) -> bool {
+        self.get_sock(sock).0.may_send()
+    }
+
+    /// Returns whether the socket can still receive data, meaning
+    /// the peer hasn't closed its side of the connection.
+    /// See [`tcp::Socket::may_recv`].
+    pub fn may_recv(&mut self, sock: SocketHandle) -> bool {
+        self.get_sock(sock).0.may_recv()
+    }
+
+    /// The number of bytes sent that the peer hasn't acknowledged yet.
+    pub fn send_queue(&mut self, sock: SocketHandle) -> usize {
+        self.get_sock(sock).0.send_queue()
+    }
+
+    /// The number of bytes received that haven't been read yet.
+    pub fn recv_queue(&mut self, sock: SocketHandle) -> usize {
+        let (sock, data) = self.get_sock(sock);
+        sock.recv_queue() + data.app_queue.len()
+    }
+
+    /// Describes the data the socket has received but can't read yet,
+    /// because a segment before it is missing. See [`ReassemblyInfo`].
+    pub fn reassembly_info(&self, sock: SocketHandle) -> ReassemblyInfo {
+        let socket = self.sockets.get::<tcp::Socket>(sock);
+        // smoltcp doesn't expose its assembler, but prints it in the socket's
+        // debug output as `Contig { hole_size: .., data_size: .. }`s, even
+        // when they're empty. That's why smoltcp is pinned in Cargo.toml.
+        let debug = format!("{socket:?}");
+        assert!(
+            debug.contains("hole_size: "),
+            "smoltcp's debug output no longer shows its assembler"
+        );
+        let mut ranges = Vec::new();
+        let mut offset = 0;
+        let mut rest = &debug[..];
+        while let Some(start) = rest.find("hole_size: ") {
+            rest = &rest[start..];
+            let hole = parse_field(rest, "hole_size: ");
+            let data = parse_field(rest, "data_size: ");
+            rest = &rest["hole_size: ".len()..];
+            if data == 0 {
+                break;
+            }
+            offset += hole;
+            ranges.push((offset, data));
+            offset += data;
+        }
+        let app_queue = self
+            .socket_data
+            .get(&sock)
+            .map_or(0, |data| data.app_queue.len());
+        ReassemblyInfo {
+            contiguous: socket.recv_queue() + app_queue,
+            ranges,
+        }
+    }
+
+    /// Stops (or restarts) calling the socket's recv callback, as if the
+    /// application stopped reading. Received data stays in the socket,
+    /// so its receive window fills up and the peer has to stop sending.
+    /// With [`RecvPolicy::DropOnFull`], data is dropped instead.
+    ///
+    /// When unpaused, the callback is called right away if data is waiting.
+    pub fn pause_recv(&mut self, sock: SocketHandle, paused: bool) {
+        let (socket, data) = self.get_sock(sock);
+        data.recv_paused = paused;
+        let can_recv = socket.can_recv() || !data.app_queue.is_empty();
+        let callback = data.callbacks.recv;
+        if !paused && can_recv {
+            callback(self, sock);
+        }
+    }
+
     pub fn set_recv_callback(&mut self, sock: SocketHandle, cb: fn(&mut ElvOs, SocketHandle)) {
         let sock_data = self.get_sock(sock).1;
-        sock_data.recv = cb;
+        sock_data.callbacks.recv = cb;
     }
 
     pub fn recv(&mut self, sock: SocketHandle) -> Msg {
-        let sock = self.get_sock(sock).0;
-        receive_all(sock)
+        let (sock, data) = self.get_sock(sock);
+        let mut msg = Vec::from_iter(data.app_queue.drain(..));
+        msg.append(&mut receive_all(sock));
+        msg
+    }
+
+    /// Collects everything the socket receives until the peer closes the
+    /// connection, then calls `cb` once with all of it. Call this after
+    /// [`connect`](ElvOs::connect) or [`listen`](ElvOs::listen).
+    ///
+    /// Until then, the recv callback isn't called. If the connection is
+    /// reset, `cb` gets whatever arrived before that.
+    pub fn recv_until_eof(&mut self, sock: SocketHandle, cb: EofCallback) {
+        let data = self.get_sock(sock).1;
+        data.until_eof = Some((Vec::new(), cb));
+    }
+
+    /// Lets the socket's receive buffer grow, up to `max` bytes, when the
+    /// receive window looks like it's limiting how fast the peer can send.
+    /// `None` turns this off.
+    ///
+    /// smoltcp can't resize a buffer during a connection, so the buffer only
+    /// grows between connections: if the buffer was ever nearly full during
+    /// one connection, the next [`connect`](ElvOs::connect) or
+    /// [`listen`](ElvOs::listen) on the closed socket doubles it.
+    /// The peer's send buffer has to be big enough to fill the window too.
+    pub fn set_recv_autotune(&mut self, sock: SocketHandle, max: Option<usize>) {
+        self.get_sock(sock).1.autotune_max = max;
+    }
+
+    /// Grows the receive buffer of a closed socket, if auto-tuning
+    /// decided it should. See [`set_recv_autotune`](ElvOs::set_recv_autotune).
+    fn autotune(&mut self, sock: SocketHandle) {
+        let (socket, data) = self.get_sock(sock);
+        let Some(max) = data.autotune_max else {
+            return;
+        };
+        let capacity = socket.recv_capacity();
+        let new_capacity = usize::min(capacity * 2, max);
+        if !data.window_limited || new_capacity <= capacity || socket.is_open() {
+            return;
+        }
+        data.window_limited = false;
+        log!("growing receive buffer from {capacity} to {new_capacity}");
+
+        // the buffers can't be swapped, so the socket is replaced,
+        // keeping its settings
+        let mut new_socket = tcp::Socket::new(
+            RingBuffer::new(vec![0; new_capacity]),
+            RingBuffer::new(vec![0; socket.send_capacity()]),
+        );
+        new_socket.set_timeout(socket.timeout());
+        new_socket.set_ack_delay(socket.ack_delay());
+        new_socket.set_nagle_enabled(socket.nagle_enabled());
+        new_socket.set_keep_alive(socket.keep_alive());
+        new_socket.set_hop_limit(socket.hop_limit());
+        *socket = new_socket;
+    }
+
+    /// Enables or disables Nagle's algorithm, which holds back small
+    /// segments while earlier data hasn't been acknowledged.
+    /// It is enabled by default.
+    pub fn set_nagle_enabled(&mut self, sock: SocketHandle, enabled: bool) {
+        self.get_sock(sock).0.set_nagle_enabled(enabled);
+    }
+
+    /// Sets how long (in microseconds) the socket waits before acknowledging
+    /// received data, in case it can send the ACK along with data of its own.
+    /// `None` acknowledges right away. The default is 10 milliseconds.
+    pub fn set_ack_delay(&mut self, sock: SocketHandle, delay: Option<Time>) {
+        let delay = delay.map(|micros| Duration::from_micros(micros as u64));
+        self.get_sock(sock).0.set_ack_delay(delay);
+    }
+
+    /// Sets the congestion control algorithm of the socket.
+    ///
+    /// smoltcp 0.11 doesn't do congestion control: a socket sends as much as
+    /// the peer's window allows, and backs off only by its retransmission
+    /// timer. So [`CongestionControl::None`] is the only choice for now, and
+    /// this does nothing. Later smoltcp versions add Reno and CUBIC.
+    pub fn set_congestion_control(&mut self, sock: SocketHandle, algorithm: CongestionControl) {
+        self.get_sock(sock);
+        match algorithm {
+            CongestionControl::None => {}
+        }
+    }
+
+    /// Makes the socket send its buffered data right now, without waiting
+    /// for Nagle's algorithm, so data sent after this goes in a new segment.
+    /// The last segment sent will have the PSH flag.
+    ///
+    /// If this is called outside of a poll, the segments are sent out
+    /// the next time this ElvOs is polled. Nothing is received while
+    /// flushing: frames waiting to be received are handled in that poll too.
+    pub fn flush(&mut self, sock: SocketHandle) {
+        let socket = self.get_sock(sock).0;
+        let nagle = socket.nagle_enabled();
+        socket.set_nagle_enabled(false);
+        // frames waiting to be received are left for the next poll,
+        // which does more with them than smoltcp alone
+        let incoming = std::mem::take(&mut self.device.incoming);
+        self.interface.poll(
+            Instant::from_micros(self.time),
+            &mut self.device,
+            &mut self.sockets,
+        );
+        self.device.incoming = incoming;
+        self.get_sock(sock).0.set_nagle_enabled(nagle);
+    }
+
+    /// Sets what happens to received data that hasn't been read yet.
+    /// See [`RecvPolicy`].
+    pub fn set_recv_policy(&mut self, sock: SocketHandle, policy: RecvPolicy) {
+        self.get_sock(sock).1.recv_policy = policy;
+    }
+
+    /// Returns the number of received bytes that were dropped
+    /// because of a [`RecvPolicy::DropOnFull`] policy.
+    pub fn dropped_bytes(&mut self, sock: SocketHandle) -> usize {
+        self.get_sock(sock).1.dropped_bytes
     }
 
     /// Sets the local IP addresses of this ElvOs.
     pub fn set_local_addrs(&mut self, addr: IpCidr) {
+        self.smoltcp_poll_at = None;
         self.interface.update_ip_addrs(|addrs| {
             assert!(addrs.is_empty(), "only one IP address can be set");
             addrs.push(addr).expect("addrs should be empty");
         })
     }
 
-    /// Asserts that a local address is set.
-    fn assert_local_set(&self) {
-        assert!(
-            !self.interface.ip_addrs().is_empty(),
-            "Ip address should be set before connecting sockets"
-        );
+    /// Adds another local IP address to this ElvOs,
+    /// in addition to any that are already set.
+    ///
+    /// Panics if the interface can't hold any more addresses.
+    pub fn add_local_addr(&mut self, addr: IpCidr) {
+        self.smoltcp_poll_at = None;
+        self.interface.update_ip_addrs(|addrs| {
+            addrs.push(addr).expect("too many IP addresses set");
+        })
+    }
+
+    /// Returns an error if no local address is set.
+    fn check_local_set(&self) -> Result<(), ElvError> {
+        if self.interface.ip_addrs().is_empty() {
+            Err(ElvError::NoLocalAddress)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Gets this ElvOs's IP address with DHCP, instead of
+    /// [`set_local_addrs`](ElvOs::set_local_addrs).
+    ///
+    /// When an address is acquired, it replaces any local addresses,
+    /// the default route is set to the DHCP server's router,
+    /// and `cb` is called with the new address.
+    pub fn enable_dhcp(&mut self, cb: DhcpCallback) {
+        assert!(self.dhcp.is_none(), "DHCP is already enabled");
+        self.smoltcp_poll_at = None;
+        let handle = self.sockets.add(dhcpv4::Socket::new());
+        self.dhcp = Some((handle, cb));
+    }
+
+    /// Applies any changes from the DHCP socket.
+    fn poll_dhcp(&mut self) {
+        let Some((handle, cb)) = self.dhcp else {
+            return;
+        };
+        let config = match self.sockets.get_mut::<dhcpv4::Socket>(handle).poll() {
+            None => return,
+            Some(dhcpv4::Event::Configured(config)) => Some((config.address, config.router)),
+            Some(dhcpv4::Event::Deconfigured) => None,
+        };
+
+        let routes = self.interface.routes_mut();
+        match config.and_then(|(_addr, router)| router) {
+            Some(router) => {
+                routes
+                    .add_default_ipv4_route(router)
+                    .expect("routes should have space");
+            }
+            None => {
+                routes.remove_default_ipv4_route();
+            }
+        }
+        self.interface.update_ip_addrs(|addrs| {
+            addrs.clear();
+            if let Some((addr, _router)) = config {
+                addrs
+                    .push(IpCidr::Ipv4(addr))
+                    .expect("addrs should be empty");
+            }
+        });
+
+        if let Some((addr, _router)) = config {
+            cb(self, IpCidr::Ipv4(addr));
+        }
+    }
+
+    /// Puts a raw ethernet frame in this ElvOs's incoming queue,
+    /// as if it had arrived from the network.
+    /// It will be handled the next time this ElvOs is polled.
+    ///
+    /// Malformed frames (bad checksums, etc.) are dropped by the stack.
+    pub fn inject_packet(&mut self, msg: Msg) {
+        self.device.incoming.push_back(msg);
+    }
+
+    /// Sets whether [`poll_at`](Node::poll_at) remembers the time smoltcp
+    /// wants to be polled at until the sockets change, instead of asking
+    /// smoltcp every time. It does by default. Turning it off is slower,
+    /// but shows whether the cache changes how a simulation runs.
+    pub fn set_poll_at_cache(&mut self, enabled: bool) {
+        self.cache_poll_at = enabled;
+        self.smoltcp_poll_at = None;
+    }
+
+    /// Limits this ElvOs to sending at most `packets_per_ms` packets every
+    /// (simulated) millisecond, like a NIC that can't send in bursts.
+    /// Extra packets wait in a queue until they can be sent.
+    /// `None` removes the limit.
+    pub fn set_egress_rate(&mut self, packets_per_ms: Option<usize>) {
+        assert!(packets_per_ms != Some(0), "egress rate must be positive");
+        self.egress_rate = packets_per_ms;
+    }
+
+    /// Handles SYNs that crossed a SYN we sent to the same endpoint,
+    /// which happens when both sides connect to each other at once.
+    ///
+    /// smoltcp 0.11 doesn't support simultaneous open: it ignores a SYN that
+    /// arrives while its own SYN is unanswered, so both sides would wait
+    /// forever. This is a workaround, not real support. The side with the
+    /// higher endpoint aborts its own connection attempt and listens, so it
+    /// answers the other side's SYN like a server would. Its socket goes
+    /// through `Listen` instead of straight from `SynSent` to `SynReceived`,
+    /// and the handshake on the wire is an ordinary three-way one.
+    fn resolve_simultaneous_opens(&mut self) {
+        let syns = self.device.incoming.iter().filter_map(|frame| {
+            let tcp = tcp_segment(frame)?;
+            (tcp.syn() && !tcp.ack()).then(|| tcp_flow(frame))?
+        });
+        let syns = Vec::from_iter(syns);
+
+        for (remote, local) in syns {
+            for (handle, sock) in self.sockets.iter_mut() {
+                let Some(sock) = downcast(sock) else {
+                    continue;
+                };
+                if sock.state() == tcp::State::SynSent
+                    && sock.local_endpoint() == Some(local)
+                    && sock.remote_endpoint() == Some(remote)
+                    && local > remote
+                {
+                    log!("simultaneous open on {handle}, listening on {local} instead");
+                    // listen resets the socket, so the abort doesn't send a RST
+                    sock.abort();
+                    sock.listen(local)
+                        .expect("a closed socket should be able to listen");
+                    self.smoltcp_poll_at = None;
+                }
+            }
+        }
+    }
+
+    /// Limits this ElvOs to accepting at most `per_sec` new connections every
+    /// (simulated) second, as a simple defense against SYN floods.
+    /// SYNs beyond the limit are refused with a RST before smoltcp sees them,
+    /// the same way smoltcp refuses a SYN when no socket is listening.
+    /// `None` removes the limit.
+    ///
+    /// Only SYNs that a listening socket would take count towards the limit.
+    /// Retransmitted SYNs for a handshake that's already started don't.
+    pub fn set_accept_rate(&mut self, per_sec: Option<usize>) {
+        self.accept_rate = per_sec;
+    }
+
+    /// The number of SYNs refused because of the accept rate limit.
+    pub fn refused_syns(&self) -> usize {
+        self.refused_syns
+    }
+
+    /// Refuses the incoming SYNs that go over the accept rate limit.
+    fn limit_accepts(&mut self) {
+        let Some(rate) = self.accept_rate else {
+            return;
+        };
+        let window = self.time / 1_000_000;
+        if window != self.accept_window {
+            self.accept_window = window;
+            self.accepted = 0;
+        }
+
+        let mut kept = VecDeque::new();
+        for frame in self.device.incoming.drain(..) {
+            let syn = tcp_segment(&frame).is_some_and(|tcp| tcp.syn() && !tcp.ack());
+            let new_connection = syn
+                && tcp_flow(&frame).is_some_and(|(remote, local)| {
+                    is_new_connection(&mut self.sockets, &self.socket_data, remote, local)
+                });
+            if !new_connection {
+                kept.push_back(frame);
+            } else if self.accepted < rate {
+                self.accepted += 1;
+                kept.push_back(frame);
+            } else {
+                log!("accept rate exceeded, refusing a connection");
+                self.refused_syns += 1;
+                self.device.outgoing.extend(reset_reply(&frame));
+            }
+        }
+        self.device.incoming = kept;
+    }
+
+    /// Makes this ElvOs wait before handling each packet it receives,
+    /// like a busy CPU would. Responses (and callbacks) are delayed as well,
+    /// since they only happen once the packet is handled.
+    /// Packets are still handled in the order they arrived.
+    pub fn set_processing_delay(&mut self, delay: Time) {
+        assert!(delay >= 0, "processing delay can't be negative");
+        self.processing_delay = delay;
+    }
+
+    /// Hands the held packets that are ready to smoltcp.
+    fn release_held(&mut self) {
+        while let Some((ready_time, _)) = self.held.front() {
+            if *ready_time <= self.time {
+                let (_, msg) = self.held.pop_front().unwrap();
+                self.device.incoming.push_back(msg);
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Takes the packets that can be sent at the current time
+    /// out of the device and egress queue.
+    fn take_egress(&mut self) -> Vec<Msg> {
+        self.egress_queue
+            .extend(take_all(&mut self.device.outgoing));
+        let Some(rate) = self.egress_rate else {
+            return Vec::from_iter(self.egress_queue.drain(..));
+        };
+
+        let window = self.time / 1000;
+        if window != self.egress_window {
+            self.egress_window = window;
+            self.egress_sent = 0;
+        }
+        let count = usize::min(rate - self.egress_sent, self.egress_queue.len());
+        self.egress_sent += count;
+        Vec::from_iter(self.egress_queue.drain(..count))
     }
 
     pub fn receiver(&self) -> Index {
@@ -224,23 +871,70 @@ impl ElvOs {
     }
 }
 
-/// downcasts a generic tcp socket to an ordinary socket
-fn downcast<'a>(sock: &'a mut smoltcp::socket::Socket<'static>) -> &'a mut tcp::Socket<'static> {
-    tcp::Socket::downcast_mut(sock).expect("should be a TCP socket")
+/// downcasts a generic socket to a TCP socket, if it is one
+fn downcast<'a>(
+    sock: &'a mut smoltcp::socket::Socket<'static>,
+) -> Option<&'a mut tcp::Socket<'static>> {
+    tcp::Socket::downcast_mut(sock)
+}
+
+/// Whether a SYN from `remote` to `local` would start a new handshake on
+/// a listening socket, rather than being part of one that's already started.
+fn is_new_connection(
+    sockets: &mut SocketSet<'static>,
+    socket_data: &HashMap<SocketHandle, SocketData>,
+    remote: IpEndpoint,
+    local: IpEndpoint,
+) -> bool {
+    let mut listening = false;
+    for (handle, sock) in sockets.iter_mut() {
+        let Some(sock) = downcast(sock) else {
+            continue;
+        };
+        if sock.local_endpoint() == Some(local) && sock.remote_endpoint() == Some(remote) {
+            return false;
+        }
+        let endpoint = socket_data
+            .get(&handle)
+            .and_then(|data| data.listen_endpoint);
+        if let (tcp::State::Listen, Some(endpoint)) = (sock.state(), endpoint) {
+            listening |=
+                endpoint.port == local.port && endpoint.addr.is_none_or(|addr| addr == local.addr);
+        }
+    }
+    listening
+}
+
+/// Parses the number after the first `name` in `text`.
+///
+/// Panics if there isn't one, since that means smoltcp's debug output
+/// has changed.
+fn parse_field(text: &str, name: &str) -> usize {
+    let start = text
+        .find(name)
+        .unwrap_or_else(|| panic!("smoltcp's debug output no longer has {name:?}"));
+    let digits = text[start + name.len()..]
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect::<String>();
+    digits
+        .parse()
+        .unwrap_or_else(|_| panic!("smoltcp's debug output has no number after {name:?}"))
 }
 
 /// Receives all data from a smoltcp socket buffer and puts it in a msg.
+/// If the socket can't be received from, the msg is empty.
 fn receive_all(sock: &mut tcp::Socket<'static>) -> Msg {
     let mut result = vec![0; sock.recv_queue()];
     let mut start = 0;
     loop {
         // stop when there's an error or no more data is received
         match sock.recv_slice(&mut result[start..]) {
-            Ok(0) => break,
-            Err(e) => panic!("(this is a demo i can panic with {e})"),
-            Ok(num) => start = num,
+            Ok(0) | Err(_) => break,
+            Ok(num) => start += num,
         }
     }
+    result.truncate(start);
     result
 }
 
@@ -251,22 +945,39 @@ impl Node for ElvOs {
         self.time = time;
 
         // save the state of the sockets (so we'll know to make the
-        // listen and connect callbacks)
+        // incoming and connect callbacks)
         let mut connecting_socks: HashSet<SocketHandle> = HashSet::new();
+        let mut listening_socks: HashSet<SocketHandle> = HashSet::new();
         for (handle, sock) in self.sockets.iter_mut() {
-            let sock = downcast(sock);
+            let Some(sock) = downcast(sock) else {
+                continue;
+            };
             match sock.state() {
                 Listen | SynSent | SynReceived => {
                     connecting_socks.insert(handle);
                 }
                 _other => {}
             }
+            if sock.state() == Listen {
+                listening_socks.insert(handle);
+            }
         }
 
         // receive incoming
-        self.device
-            .incoming
-            .extend(incoming.into_iter().map(|(_index, msg)| msg));
+        // packets that arrive while others are held have to wait their turn
+        let hold = self.processing_delay > 0 || !self.held.is_empty();
+        if hold && !incoming.is_empty() {
+            let ready_time = time + self.processing_delay;
+            self.held
+                .extend(incoming.into_iter().map(|(_index, msg)| (ready_time, msg)));
+            self.add_event(ready_time, ElvOs::release_held);
+        } else {
+            self.device
+                .incoming
+                .extend(incoming.into_iter().map(|(_index, msg)| msg));
+        }
+        self.resolve_simultaneous_opens();
+        self.limit_accepts();
         // poll smoltcp
         self.interface.poll(
             Instant::from_micros(time),
@@ -274,19 +985,74 @@ impl Node for ElvOs {
             &mut self.sockets,
         );
 
+        self.poll_dhcp();
+
         // make connect and receive callbacks
-        let handles = Vec::from_iter(self.sockets.iter().map(|(handle, _sock)| handle));
+        let handles = self.sockets.iter().map(|(handle, _sock)| handle);
+        let handles =
+            Vec::from_iter(handles.filter(|handle| self.socket_data.contains_key(handle)));
         for handle in handles {
             let (socket, data) = self.get_sock(handle);
-            let data = *data;
-            let can_recv = socket.can_recv();
+            // a nearly full buffer means the peer had to wait for the window
+            if socket.recv_queue() * 4 >= socket.recv_capacity() * 3 {
+                data.window_limited = true;
+            }
+            if let RecvPolicy::DropOnFull { capacity } = data.recv_policy {
+                let received = receive_all(socket);
+                let fits = usize::min(
+                    received.len(),
+                    capacity.saturating_sub(data.app_queue.len()),
+                );
+                data.app_queue.extend(&received[..fits]);
+                data.dropped_bytes += received.len() - fits;
+            }
+            // data being collected by recv_until_eof skips the recv callback
+            let mut eof = None;
+            if let Some((buffer, _callback)) = &mut data.until_eof {
+                buffer.extend(data.app_queue.drain(..));
+                buffer.append(&mut receive_all(socket));
+                let connecting = matches!(socket.state(), Listen | SynSent | SynReceived);
+                if !socket.may_recv() && !connecting {
+                    eof = data.until_eof.take();
+                }
+            }
+            let callbacks = data.callbacks;
+            let can_recv = (socket.can_recv() || !data.app_queue.is_empty()) && !data.recv_paused;
+            let drained = data.sending && socket.send_queue() == 0;
+            data.sending = socket.send_queue() > 0;
+            if socket.state() == TimeWait {
+                data.time_wait_since.get_or_insert(time);
+            } else {
+                data.time_wait_since = None;
+            }
+
+            let state = socket.state();
+            let peer = socket.remote_endpoint();
+            let established = state == Established && connecting_socks.contains(&handle);
+            if established {
+                data.established_at.get_or_insert(time);
+            }
 
-            if socket.state() == Established && connecting_socks.contains(&handle) {
-                (data.connect)(self, handle)
+            let incoming =
+                matches!(state, SynReceived | Established) && listening_socks.contains(&handle);
+            if let (true, Some(peer)) = (incoming, peer) {
+                (callbacks.incoming)(self, handle, peer)
+            }
+
+            if established {
+                (callbacks.connect)(self, handle)
             }
 
             if can_recv {
-                (data.recv)(self, handle)
+                (callbacks.recv)(self, handle)
+            }
+
+            if drained {
+                (callbacks.drained)(self, handle)
+            }
+
+            if let Some((buffer, callback)) = eof {
+                callback(self, handle, buffer)
             }
         }
 
@@ -300,19 +1066,44 @@ impl Node for ElvOs {
             }
         }
 
+        // polling (and the callbacks) changed the sockets
+        self.smoltcp_poll_at = None;
+
         // send outgoing data
-        let outgoing = take_all(&mut self.device.outgoing);
+        let outgoing = self.take_egress();
         Vec::from_iter(outgoing.into_iter().map(|msg| (self.receiver, msg)))
     }
 
     fn poll_at(&mut self) -> Option<Time> {
-        let smoltcp_poll_time = self
-            .interface
-            .poll_at(Instant::from_micros(self.time), &self.sockets);
-        let smoltcp_poll_time = smoltcp_poll_time.map(|time| time.total_micros());
-        // TODO: make github pull request to document weird smoltcp poll_at behavior
-        let smoltcp_poll_time = smoltcp_poll_time.map(|t| Time::max(self.time, t));
-        let events_poll_time = self.events.peek().map(|event| event.0);
+        let cached = self.smoltcp_poll_at.filter(|_| self.cache_poll_at);
+        let smoltcp_poll_time = match cached {
+            Some(cached) => cached,
+            None => {
+                let smoltcp_poll_time = self
+                    .interface
+                    .poll_at(Instant::from_micros(self.time), &self.sockets);
+                let smoltcp_poll_time = smoltcp_poll_time.map(|time| time.total_micros());
+                // TODO: make github pull request to document weird smoltcp poll_at behavior
+                let smoltcp_poll_time = smoltcp_poll_time.map(|t| Time::max(self.time, t));
+                self.smoltcp_poll_at = Some(smoltcp_poll_time);
+                smoltcp_poll_time
+            }
+        };
+        let events_poll_time = self.next_event_time();
+        // injected and flushed packets should be handled right away
+        let device_idle = self.device.incoming.is_empty() && self.device.outgoing.is_empty();
+        let smoltcp_poll_time = if device_idle {
+            smoltcp_poll_time
+        } else {
+            Some(self.time)
+        };
+        // queued packets can be sent once the next millisecond starts
+        let smoltcp_poll_time = if self.egress_queue.is_empty() {
+            smoltcp_poll_time
+        } else {
+            let next_window = (self.egress_window + 1) * 1000;
+            Some(smoltcp_poll_time.map_or(next_window, |t| Time::min(t, next_window)))
+        };
 
         // choose earliest of 2 times
         match (smoltcp_poll_time, events_poll_time) {
@@ -322,54 +1113,121 @@ impl Node for ElvOs {
             (None, None) => None,
         }
     }
+
+    fn next_event_time(&self) -> Option<Time> {
+        self.events.peek().map(|event| event.0)
+    }
 }
 
 type Callback = fn(&mut ElvOs, SocketHandle);
 
-#[derive(Clone, Copy)]
+/// Called with everything a socket received, once the peer has closed.
+pub type EofCallback = fn(&mut ElvOs, SocketHandle, Msg);
+
+/// Called with a listening socket and the peer trying to connect to it.
+pub type IncomingCallback = fn(&mut ElvOs, SocketHandle, IpEndpoint);
+
+/// Called with the address an ElvOs got from DHCP.
+pub type DhcpCallback = fn(&mut ElvOs, IpCidr);
+
+#[derive(Default)]
 struct SocketData {
-    /// Callbacks, set by `set_connect_callback`, etc.
+    callbacks: Callbacks,
+    recv_policy: RecvPolicy,
+    /// Data taken out of the socket that the application hasn't received yet.
+    /// Only used with [`RecvPolicy::DropOnFull`].
+    app_queue: VecDeque<u8>,
+    /// The number of bytes dropped because `app_queue` was full.
+    dropped_bytes: usize,
+    /// Whether the socket has sent data that hasn't been acknowledged yet.
+    sending: bool,
+    /// Whether the recv callback is paused.
+    recv_paused: bool,
+    /// The time of the last `connect` or `listen` call.
+    handshake_started: Option<Time>,
+    /// The time the socket was first seen established after that call.
+    established_at: Option<Time>,
+    /// The time the socket was first seen in TIME-WAIT, if it's in it.
+    time_wait_since: Option<Time>,
+    /// The endpoint given to the last `listen` call.
+    listen_endpoint: Option<IpListenEndpoint>,
+    /// The biggest the receive buffer can grow, if it can.
+    autotune_max: Option<usize>,
+    /// Whether the receive buffer has been nearly full
+    /// since it was last grown.
+    window_limited: bool,
+    /// The data collected so far by `recv_until_eof`, and the callback
+    /// to give it to.
+    until_eof: Option<(Msg, EofCallback)>,
+}
+
+/// Callbacks, set by `set_connect_callback`, etc.
+#[derive(Clone, Copy)]
+struct Callbacks {
+    incoming: IncomingCallback,
     connect: Callback,
     recv: Callback,
+    drained: Callback,
 }
 
-impl Default for SocketData {
+impl Default for Callbacks {
     fn default() -> Self {
         fn nothing(_: &mut ElvOs, _: SocketHandle) {}
+        fn nothing_incoming(_: &mut ElvOs, _: SocketHandle, _: IpEndpoint) {}
         Self {
+            incoming: nothing_incoming,
             connect: nothing,
             recv: nothing,
+            drained: nothing,
         }
     }
 }
 
-/// Removes all values in the given vec, and puts them in the returned vec.
-pub fn take_all(v: &mut Vec<Msg>) -> Vec<Msg> {
-    let mut result = Vec::new();
-    std::mem::swap(&mut result, v);
-    result
+/// Data a socket has received, split into what can be read and what is
+/// waiting for a gap in the sequence to be filled.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReassemblyInfo {
+    /// The number of bytes that can be read right now.
+    pub contiguous: usize,
+    /// The data received out of order, as `(offset, len)` ranges.
+    /// Offsets are from the end of the readable data, so the first range
+    /// starts after a gap. smoltcp tracks at most 4 ranges, and drops
+    /// segments that would need more.
+    pub ranges: Vec<(usize, usize)>,
 }
 
-/// An event is just a function and the time it gets called.
-/// Ordered so that the earliest events come first in Rust's BinaryHeap.
-struct Event(Time, Box<dyn FnOnce(&mut ElvOs)>);
-
-impl PartialEq for Event {
-    fn eq(&self, other: &Self) -> bool {
-        self.0.eq(&other.0)
+impl ReassemblyInfo {
+    /// The number of bytes received out of order.
+    pub fn out_of_order(&self) -> usize {
+        self.ranges.iter().map(|(_offset, len)| len).sum()
     }
 }
 
-impl Eq for Event {}
+/// A congestion control algorithm. See [`ElvOs::set_congestion_control`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CongestionControl {
+    /// No congestion control, which is all smoltcp 0.11 has.
+    #[default]
+    None,
+}
 
-impl PartialOrd for Event {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
+/// What happens to received data that the application hasn't read yet.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RecvPolicy {
+    /// Data stays in the socket's buffer. When the buffer is full,
+    /// the TCP window closes and the sender has to wait.
+    #[default]
+    Backpressure,
+    /// Data is moved out of the socket into a queue that holds at most
+    /// `capacity` bytes, and anything that doesn't fit is dropped.
+    /// The TCP window never closes, like a lossy consumer.
+    DropOnFull { capacity: usize },
 }
 
-impl Ord for Event {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.0.cmp(&other.0).reverse()
-    }
+/// Removes all values in the given vec, and puts them in the returned vec.
+pub fn take_all(v: &mut Vec<Msg>) -> Vec<Msg> {
+    let mut result = Vec::new();
+    std::mem::swap(&mut result, v);
+    result
 }
@@ -0,0 +1,278 @@
+//! Ready-made setups for tests.
+
+use std::{fs, path::Path};
+
+use smoltcp::{
+    iface::SocketHandle,
+    socket::tcp::State,
+    wire::{EthernetAddress, IpAddress, IpCidr, IpEndpoint, Ipv4Address},
+};
+
+use crate::packet::tcp_segment;
+use crate::simulator::{
+    run_sim_until, run_sim_until_predicate, trace_to_string, Delivery, Index, Node, SimStats,
+    Simulation, Time,
+};
+use crate::stream::{StreamSender, StreamVerifier};
+use crate::tcp_machine::{CongestionControl, ElvOs};
+use crate::wire::{Wire, WireConfig};
+
+pub const CLIENT_END: IpEndpoint = IpEndpoint {
+    addr: IpAddress::Ipv4(Ipv4Address([10, 0, 0, 1])),
+    port: 50000,
+};
+
+pub const SERVER_END: IpEndpoint = IpEndpoint {
+    addr: IpAddress::Ipv4(Ipv4Address([10, 0, 0, 2])),
+    port: 80,
+};
+
+/// Makes a host with the MAC address `00-00-00-00-00-{mac}` and the given
+/// address (on a /24), that sends everything to node `receiver`.
+pub fn host(mac: u8, addr: IpAddress, receiver: Index) -> ElvOs {
+    let mut os = ElvOs::new(0, receiver, EthernetAddress([0, 0, 0, 0, 0, mac]));
+    os.set_local_addrs(IpCidr::new(addr, 24));
+    os
+}
+
+/// Two hosts with a connected socket each, joined by a zero-delay wire.
+/// The client is node 0, the server is node 1, and the wire is node 2.
+pub struct ConnectedPair {
+    pub client: ElvOs,
+    pub server: ElvOs,
+    pub wire: Wire,
+    pub client_sock: SocketHandle,
+    pub server_sock: SocketHandle,
+}
+
+impl ConnectedPair {
+    /// Runs the simulation of the pair until the given time has passed.
+    pub fn run_until(&mut self, end_time: Time) {
+        run_sim_until(&mut self.nodes(), end_time);
+    }
+
+    fn nodes(&mut self) -> [&mut dyn Node; 3] {
+        [&mut self.client, &mut self.server, &mut self.wire]
+    }
+}
+
+/// Creates a [`ConnectedPair`], and runs the simulation until
+/// both sockets are established.
+///
+/// Panics if the handshake doesn't finish within a simulated second.
+pub fn connect_pair() -> ConnectedPair {
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+
+    let server_sock = server.socket();
+    server
+        .listen(server_sock, SERVER_END)
+        .expect("listen should succeed");
+    let client_sock = client.socket();
+    client
+        .connect(client_sock, CLIENT_END, SERVER_END)
+        .expect("connect should succeed");
+
+    let mut pair = ConnectedPair {
+        client,
+        server,
+        wire: Wire::new(0, 1, 0),
+        client_sock,
+        server_sock,
+    };
+
+    let established = run_sim_until_predicate(&mut pair.nodes(), 1_000_000, |nodes| {
+        let client = nodes[0].downcast_ref::<ElvOs>().unwrap();
+        let server = nodes[1].downcast_ref::<ElvOs>().unwrap();
+        client.state(client_sock) == State::Established
+            && server.state(server_sock) == State::Established
+    });
+    assert!(established, "the pair should connect");
+    pair
+}
+
+/// Settings for one run of [`compare_transfers`].
+#[derive(Clone, Copy, Debug)]
+pub struct TransferOptions {
+    pub congestion_control: CongestionControl,
+    /// Whether the sender uses Nagle's algorithm.
+    pub nagle: bool,
+    /// The receiver's ACK delay. See [`ElvOs::set_ack_delay`].
+    pub ack_delay: Option<Time>,
+    /// The size of the sender's send buffer.
+    pub send_buffer: usize,
+    /// The size of the receiver's receive buffer, which limits its window.
+    pub recv_buffer: usize,
+}
+
+impl Default for TransferOptions {
+    /// smoltcp's defaults, with the buffers of [`ElvOs::socket`].
+    fn default() -> Self {
+        TransferOptions {
+            congestion_control: CongestionControl::None,
+            nagle: true,
+            ack_delay: Some(10_000),
+            send_buffer: 1500,
+            recv_buffer: 1500,
+        }
+    }
+}
+
+/// How a transfer run by [`compare_transfers`] went.
+#[derive(Clone, Debug)]
+pub struct TransferResult {
+    pub options: TransferOptions,
+    /// When the receiver saw the sender close, after getting every byte.
+    /// `None` if that didn't happen before the end time.
+    pub completion_time: Option<Time>,
+    /// The number of data segments the sender sent again.
+    pub retransmits: usize,
+    pub stats: SimStats,
+}
+
+/// Sends `len` bytes from a client to a server over a wire made from
+/// `wire`, once with each of the options, and reports how each went.
+/// Every run gets an identical wire, so differences come from the options.
+///
+/// Each transfer is given until `end_time` to finish.
+pub fn compare_transfers(
+    wire: &WireConfig,
+    len: usize,
+    options: &[TransferOptions],
+    end_time: Time,
+) -> Vec<TransferResult> {
+    Vec::from_iter(
+        options
+            .iter()
+            .map(|options| run_transfer(wire, len, *options, end_time)),
+    )
+}
+
+/// Runs one transfer for [`compare_transfers`].
+fn run_transfer(
+    wire: &WireConfig,
+    len: usize,
+    options: TransferOptions,
+    end_time: Time,
+) -> TransferResult {
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+
+    let sock = client.socket_with_buffers(1500, options.send_buffer);
+    let mut sender = StreamSender::with_socket(client, sock, CLIENT_END, SERVER_END, 0, len)
+        .expect("connect should succeed");
+    sender
+        .os()
+        .set_congestion_control(sock, options.congestion_control);
+    sender.os().set_nagle_enabled(sock, options.nagle);
+    let sock = server.socket_with_buffers(options.recv_buffer, 1500);
+    let mut verifier =
+        StreamVerifier::with_socket(server, sock, SERVER_END, 0).expect("listen should succeed");
+    verifier.os().set_ack_delay(sock, options.ack_delay);
+    let mut wire = Wire::from_config(0, 1, wire);
+
+    // the end of the furthest data the sender has sent so far
+    let mut sent_up_to = None;
+    let mut retransmits = 0;
+    let mut sim = Simulation::new(vec![&mut sender, &mut verifier, &mut wire]);
+    sim.set_observer(|_time, from, _to, msg| {
+        let Some(tcp) = tcp_segment(msg).filter(|tcp| from == 0 && !tcp.payload().is_empty())
+        else {
+            return;
+        };
+        let start = tcp.seq_number();
+        let end = start + tcp.payload().len();
+        match sent_up_to {
+            Some(up_to) if start < up_to => retransmits += 1,
+            _ => sent_up_to = Some(end),
+        }
+    });
+    let finished = sim.run_until_predicate(end_time, |nodes| {
+        nodes[1]
+            .downcast_ref::<StreamVerifier>()
+            .unwrap()
+            .finished()
+    });
+    let completion_time = finished.then(|| sim.current_time());
+    let stats = sim.stats();
+    drop(sim);
+
+    TransferResult {
+        options,
+        completion_time,
+        retransmits,
+        stats,
+    }
+}
+
+/// Runs a simulation of the nodes until the given time has passed,
+/// and returns every message sent, in the order they were sent.
+/// Unlike [`Simulation::run_until_packets`], this includes the messages
+/// sent to links.
+pub fn record_trace(nodes: &mut [&mut dyn Node], end_time: Time) -> Vec<Delivery> {
+    let mut trace = Vec::new();
+    let nodes = Vec::from_iter(nodes.iter_mut().map(|node| &mut **node as &mut dyn Node));
+    let mut sim = Simulation::new(nodes);
+    sim.set_observer(|time, from, to, msg| {
+        trace.push(Delivery {
+            time,
+            from,
+            to,
+            msg: msg.clone(),
+        })
+    });
+    sim.run_until(end_time);
+    drop(sim);
+    trace
+}
+
+/// Checks `actual` against the golden file at `path`, panicking with the
+/// first line that differs.
+///
+/// If the `UPDATE_GOLDEN` environment variable is set, the file is
+/// (re)written with `actual` instead, so a new golden file can be made, or
+/// an intended change accepted, by running the test again with it set.
+/// Otherwise a missing file is an error too, so a golden file that was
+/// never committed can't make the test pass.
+pub fn check_golden(path: impl AsRef<Path>, actual: &str) {
+    let path = path.as_ref();
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).expect("golden file directory should be creatable");
+        }
+        fs::write(path, actual).expect("golden file should be writable");
+        return;
+    }
+    assert!(
+        path.exists(),
+        "{} doesn't exist (set UPDATE_GOLDEN to create it)",
+        path.display()
+    );
+    let expected = fs::read_to_string(path).expect("golden file should be readable");
+    if expected == actual {
+        return;
+    }
+
+    let mut expected_lines = expected.lines();
+    let mut actual_lines = actual.lines();
+    for line in 1.. {
+        match (expected_lines.next(), actual_lines.next()) {
+            (Some(want), Some(got)) if want == got => continue,
+            (want, got) => panic!(
+                "{} differs at line {line}:\nexpected: {}\n     got: {}\n\
+                 (set UPDATE_GOLDEN to accept the change)",
+                path.display(),
+                want.unwrap_or("<end of file>"),
+                got.unwrap_or("<end of trace>"),
+            ),
+        }
+    }
+}
+
+/// Runs the nodes until the given time has passed, and checks the trace
+/// of every message sent against the golden file at `path`.
+/// See [`record_trace`] and [`check_golden`].
+pub fn check_trace(nodes: &mut [&mut dyn Node], end_time: Time, path: impl AsRef<Path>) {
+    let trace = record_trace(nodes, end_time);
+    check_golden(path, &trace_to_string(&trace));
+}
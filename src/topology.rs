@@ -0,0 +1,341 @@
+//! Loading hosts and wires from a text description, so scenarios can be
+//! shared and changed without recompiling.
+//!
+//! Each line is a command, and `#` starts a comment:
+//!
+//! ```text
+//! # host <name> <ip/prefix> <mac>
+//! host client 10.0.0.1/24 02-00-00-00-00-01
+//! host server 10.0.0.2/24 02-00-00-00-00-02
+//!
+//! # wire <host> <host> [delay=<us>] [jitter=<us>] [seed=<n>]
+//! #      [loss=<probability>] [bandwidth=<bits/s>] [buffer=<messages>]
+//! wire client server delay=1000
+//!
+//! # listen <host> <local endpoint>
+//! listen server 10.0.0.2:80
+//!
+//! # connect <time> <host> <local endpoint> <remote endpoint>
+//! connect 45000 client 10.0.0.1:50000 10.0.0.2:80
+//! ```
+//!
+//! Hosts are nodes `0..n` in the order they are declared, and wires come
+//! after them. Every host needs exactly one wire, since an [`ElvOs`]
+//! sends everything to a single node. Each port on a host can only be
+//! used by one `listen` or `connect` line, and `connect` lines need
+//! nonzero ports and a remote address.
+
+use std::{collections::HashMap, fmt};
+
+use smoltcp::{
+    iface::SocketHandle,
+    wire::{EthernetAddress, IpCidr, IpEndpoint},
+};
+
+use crate::simulator::{run_sim_until, Index, Node, Time};
+use crate::tcp_machine::ElvOs;
+use crate::wire::{JitterModel, Wire, WireConfig};
+
+/// A problem with a topology description.
+#[derive(Debug)]
+pub struct TopologyError {
+    /// The line the problem is on, starting from 1.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for TopologyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for TopologyError {}
+
+/// Hosts and wires built from a topology description.
+pub struct Topology {
+    pub hosts: Vec<ElvOs>,
+    pub wires: Vec<Wire>,
+    /// The index of each host, by name.
+    names: HashMap<String, Index>,
+    /// The sockets made by `listen` and `connect` lines,
+    /// by host index and local port.
+    sockets: HashMap<(Index, u16), SocketHandle>,
+}
+
+struct HostLine {
+    line: usize,
+    name: String,
+    addr: IpCidr,
+    hardware_addr: EthernetAddress,
+}
+
+struct WireLine {
+    line: usize,
+    ends: (Index, Index),
+    config: WireConfig,
+}
+
+enum SocketLine {
+    Listen {
+        host: Index,
+        local: IpEndpoint,
+    },
+    Connect {
+        time: Time,
+        host: Index,
+        local: IpEndpoint,
+        remote: IpEndpoint,
+    },
+}
+
+impl Topology {
+    /// Builds the hosts and wires in a topology description.
+    pub fn parse(text: &str) -> Result<Topology, TopologyError> {
+        let lines = text
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.split('#').next().unwrap().trim()))
+            .filter(|(_, line)| !line.is_empty());
+
+        // hosts are parsed first, so the other lines can refer to them
+        // no matter where they are declared
+        let mut hosts = Vec::new();
+        let mut other_lines = Vec::new();
+        for (line_num, line) in lines {
+            let words = Vec::from_iter(line.split_whitespace());
+            let error = |message: String| TopologyError {
+                line: line_num,
+                message,
+            };
+            match words[..] {
+                ["host", name, addr, hardware_addr] => {
+                    if hosts.iter().any(|host: &HostLine| host.name == name) {
+                        return Err(error(format!("host {name} is declared twice")));
+                    }
+                    hosts.push(HostLine {
+                        line: line_num,
+                        name: name.to_string(),
+                        addr: parse(addr, "IP address").map_err(error)?,
+                        hardware_addr: parse(hardware_addr, "MAC address").map_err(error)?,
+                    });
+                }
+                ["host", ..] => return Err(error("expected host <name> <ip/prefix> <mac>".into())),
+                _ => other_lines.push((line_num, words)),
+            }
+        }
+        let names = HashMap::from_iter(
+            hosts
+                .iter()
+                .enumerate()
+                .map(|(i, host)| (host.name.clone(), i)),
+        );
+
+        let mut wires = Vec::new();
+        let mut socket_lines = Vec::new();
+        for (line_num, words) in other_lines {
+            let error = |message: String| TopologyError {
+                line: line_num,
+                message,
+            };
+            let host = |name: &str| {
+                names
+                    .get(name)
+                    .copied()
+                    .ok_or_else(|| error(format!("there is no host named {name}")))
+            };
+            match words[..] {
+                ["wire", end1, end2, ref options @ ..] => {
+                    let mut wire = WireLine {
+                        line: line_num,
+                        ends: (host(end1)?, host(end2)?),
+                        config: WireConfig::default(),
+                    };
+                    for option in options {
+                        let Some((key, value)) = option.split_once('=') else {
+                            return Err(error(format!("expected <option>=<value>, not {option}")));
+                        };
+                        let config = &mut wire.config;
+                        match key {
+                            "delay" => config.delay = parse(value, "delay").map_err(error)?,
+                            "jitter" => {
+                                config.jitter = match parse(value, "jitter").map_err(error)? {
+                                    0 => JitterModel::None,
+                                    max => JitterModel::Uniform { max },
+                                }
+                            }
+                            "seed" => config.seed = parse(value, "seed").map_err(error)?,
+                            "loss" => {
+                                let loss = parse(value, "loss").map_err(error)?;
+                                if !(0.0..=1.0).contains(&loss) {
+                                    return Err(error(format!(
+                                        "loss must be between 0 and 1, not {value}"
+                                    )));
+                                }
+                                config.loss = loss;
+                            }
+                            "bandwidth" => {
+                                config.bandwidth = Some(parse(value, "bandwidth").map_err(error)?)
+                            }
+                            "buffer" => {
+                                config.buffer_limit = Some(parse(value, "buffer").map_err(error)?)
+                            }
+                            _ => return Err(error(format!("unknown wire option {key}"))),
+                        }
+                    }
+                    wires.push(wire);
+                }
+                ["listen", name, local] => socket_lines.push((
+                    line_num,
+                    SocketLine::Listen {
+                        host: host(name)?,
+                        local: parse(local, "endpoint").map_err(error)?,
+                    },
+                )),
+                ["connect", time, name, local, remote] => {
+                    let time = parse(time, "time").map_err(error)?;
+                    let local: IpEndpoint = parse(local, "endpoint").map_err(error)?;
+                    let remote: IpEndpoint = parse(remote, "endpoint").map_err(error)?;
+                    // these would only fail when the connection is made,
+                    // in the middle of the simulation
+                    if time < 0 {
+                        return Err(error(format!("time can't be negative: {time}")));
+                    }
+                    if local.port == 0 || remote.port == 0 {
+                        return Err(error("connect needs nonzero ports".into()));
+                    }
+                    if remote.addr.is_unspecified() {
+                        return Err(error(format!("can't connect to {remote}")));
+                    }
+                    socket_lines.push((
+                        line_num,
+                        SocketLine::Connect {
+                            time,
+                            host: host(name)?,
+                            local,
+                            remote,
+                        },
+                    ))
+                }
+                [command, ..] => return Err(error(format!("unknown command {command}"))),
+                [] => unreachable!("empty lines are skipped"),
+            }
+        }
+
+        // each host sends to the wire it's on
+        let mut receivers = vec![None; hosts.len()];
+        for (i, wire) in wires.iter().enumerate() {
+            for end in [wire.ends.0, wire.ends.1] {
+                if receivers[end].replace(hosts.len() + i).is_some() {
+                    return Err(TopologyError {
+                        line: wire.line,
+                        message: format!("host {} is on more than one wire", hosts[end].name),
+                    });
+                }
+            }
+        }
+
+        let mut topology = Topology {
+            hosts: Vec::new(),
+            wires: Vec::new(),
+            names,
+            sockets: HashMap::new(),
+        };
+        for (host, receiver) in hosts.iter().zip(receivers) {
+            let Some(receiver) = receiver else {
+                return Err(TopologyError {
+                    line: host.line,
+                    message: format!("host {} isn't on a wire", host.name),
+                });
+            };
+            let mut os = ElvOs::new(0, receiver, host.hardware_addr);
+            os.set_local_addrs(host.addr);
+            topology.hosts.push(os);
+        }
+        for wire in wires {
+            let (end1, end2) = wire.ends;
+            topology
+                .wires
+                .push(Wire::from_config(end1, end2, &wire.config));
+        }
+        for (line, socket_line) in socket_lines {
+            topology
+                .add_socket(socket_line)
+                .map_err(|message| TopologyError { line, message })?;
+        }
+        Ok(topology)
+    }
+
+    /// Makes the socket for a `listen` or `connect` line.
+    fn add_socket(&mut self, socket_line: SocketLine) -> Result<(), String> {
+        let (SocketLine::Listen { host, local } | SocketLine::Connect { host, local, .. }) =
+            socket_line;
+        if self.sockets.contains_key(&(host, local.port)) {
+            let name = self.names.iter().find(|(_, &i)| i == host).unwrap().0;
+            return Err(format!(
+                "host {name} already has a socket on port {}",
+                local.port
+            ));
+        }
+        match socket_line {
+            SocketLine::Listen { host, local } => {
+                let os = &mut self.hosts[host];
+                let sock = os.socket();
+                os.listen(sock, local).map_err(|e| e.to_string())?;
+                self.sockets.insert((host, local.port), sock);
+            }
+            SocketLine::Connect {
+                time,
+                host,
+                local,
+                remote,
+            } => {
+                let os = &mut self.hosts[host];
+                let sock = os.socket();
+                os.add_event(time, move |os| {
+                    os.connect(sock, local, remote)
+                        .expect("connect lines are checked when parsing")
+                });
+                self.sockets.insert((host, local.port), sock);
+            }
+        }
+        Ok(())
+    }
+
+    /// The index of the host with the given name.
+    pub fn index(&self, name: &str) -> Option<Index> {
+        self.names.get(name).copied()
+    }
+
+    /// The host with the given name.
+    pub fn host(&mut self, name: &str) -> Option<&mut ElvOs> {
+        let index = self.index(name)?;
+        Some(&mut self.hosts[index])
+    }
+
+    /// The socket made by a `listen` or `connect` line on the given host
+    /// and local port.
+    pub fn socket(&self, name: &str, port: u16) -> Option<SocketHandle> {
+        let index = self.index(name)?;
+        self.sockets.get(&(index, port)).copied()
+    }
+
+    /// All the nodes, in index order.
+    pub fn nodes(&mut self) -> Vec<&mut dyn Node> {
+        let hosts = self.hosts.iter_mut().map(|host| host as &mut dyn Node);
+        let wires = self.wires.iter_mut().map(|wire| wire as &mut dyn Node);
+        Vec::from_iter(hosts.chain(wires))
+    }
+
+    /// Runs a simulation of the topology until the given time has passed.
+    pub fn run_until(&mut self, end_time: Time) {
+        run_sim_until(&mut self.nodes(), end_time);
+    }
+}
+
+/// Parses a value, or describes what was wrong with it.
+fn parse<T: std::str::FromStr>(value: &str, what: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid {what}: {value}"))
+}
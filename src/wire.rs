@@ -1,51 +1,533 @@
-use std::collections::VecDeque;
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap, VecDeque},
+};
 
-use crate::simulator::{IncomingMsgs, Index, Msg, Node, OutgoingMsgs, Time};
+use smoltcp::wire::IpEndpoint;
 
-/// Represents an outgoing message.s
+use crate::log;
+use crate::packet::tcp_flow;
+use crate::rng::Rng;
+use crate::simulator::{Event, IncomingMsgs, Index, Msg, Node, OutgoingMsgs, Time};
+
+/// Represents an outgoing message.
 /// Ordered so that the earliest events come first in Rust's BinaryHeap.
-struct OutgoingMsg(Time, Index, Msg);
+/// Messages arriving at the same time are sent in the order they were
+/// put on the wire (the `u64` is a sequence number).
+struct OutgoingMsg(Time, u64, Index, Msg);
+
+impl PartialEq for OutgoingMsg {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OutgoingMsg {}
+
+impl PartialOrd for OutgoingMsg {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OutgoingMsg {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.0, self.1).cmp(&(other.0, other.1)).reverse()
+    }
+}
+
+/// How much extra delay (on top of the wire's fixed delay)
+/// each message gets. Jitter can make messages arrive out of order.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum JitterModel {
+    /// No jitter, every message takes exactly the wire's delay.
+    #[default]
+    None,
+    /// Extra delay picked uniformly from `0..=max`.
+    Uniform { max: Time },
+    /// Extra delay from a normal distribution. Negative samples become 0.
+    Normal { mean: Time, std_dev: Time },
+    /// A two-state Gilbert-Elliott model, for bursty delay.
+    ///
+    /// The wire is either in a good or a bad state, and picks extra delay
+    /// uniformly from `0..=good_max` or `0..=bad_max` depending on the state.
+    /// Before every message, it switches state with the given probability.
+    GilbertElliott {
+        good_max: Time,
+        bad_max: Time,
+        good_to_bad: f64,
+        bad_to_good: f64,
+    },
+}
+
+/// Picks how much extra delay (on top of the wire's fixed delay) each
+/// message gets. Implement this for delays the built-in models can't make.
+/// Closures taking the same arguments implement it too.
+pub trait DelayModel {
+    /// Picks the extra delay for a message that starts propagating at `now`.
+    /// Random choices should come from `rng`, the wire's source of
+    /// randomness, so runs with the same seed are the same.
+    fn next_delay(&mut self, now: Time, rng: &mut Rng) -> Time;
+}
+
+impl<F: FnMut(Time, &mut Rng) -> Time> DelayModel for F {
+    fn next_delay(&mut self, now: Time, rng: &mut Rng) -> Time {
+        self(now, rng)
+    }
+}
+
+/// A [`DelayModel`] following a [`JitterModel`], along with its state.
+#[derive(Clone, Debug)]
+pub struct Jitter {
+    model: JitterModel,
+    /// Whether a Gilbert-Elliott model is in its bad state.
+    bad: bool,
+}
+
+impl Jitter {
+    pub fn new(model: JitterModel) -> Jitter {
+        Jitter { model, bad: false }
+    }
+}
+
+impl DelayModel for Jitter {
+    fn next_delay(&mut self, _now: Time, rng: &mut Rng) -> Time {
+        match self.model {
+            JitterModel::None => 0,
+            JitterModel::Uniform { max } => rng.range(0, max),
+            JitterModel::Normal { mean, std_dev } => {
+                let sample = rng.normal(mean as f64, std_dev as f64);
+                Time::max(0, sample.round() as Time)
+            }
+            JitterModel::GilbertElliott {
+                good_max,
+                bad_max,
+                good_to_bad,
+                bad_to_good,
+            } => {
+                let switch = if self.bad { bad_to_good } else { good_to_bad };
+                if rng.chance(switch) {
+                    self.bad = !self.bad;
+                }
+                let max = if self.bad { bad_max } else { good_max };
+                rng.range(0, max)
+            }
+        }
+    }
+}
+
+/// A [`DelayModel`] that replays a list of delays, like ones measured on
+/// a real network. It starts over when it gets to the end.
+#[derive(Clone, Debug)]
+pub struct DelayTrace {
+    delays: Vec<Time>,
+    next: usize,
+}
+
+impl DelayTrace {
+    pub fn new(delays: Vec<Time>) -> DelayTrace {
+        assert!(!delays.is_empty(), "a delay trace needs at least one delay");
+        assert!(delays.iter().all(|&delay| delay >= 0));
+        DelayTrace { delays, next: 0 }
+    }
+}
+
+impl DelayModel for DelayTrace {
+    fn next_delay(&mut self, _now: Time, _rng: &mut Rng) -> Time {
+        let delay = self.delays[self.next];
+        self.next = (self.next + 1) % self.delays.len();
+        delay
+    }
+}
+
+/// How a wire picks which waiting message to transmit next.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueueDiscipline {
+    /// Messages are transmitted in the order they arrived.
+    #[default]
+    Fifo,
+    /// Messages are sorted into flows by their TCP endpoints, and the flows
+    /// take turns transmitting a message each. Messages that aren't TCP
+    /// segments share one flow.
+    FairQueuing,
+}
+
+/// The endpoints of a TCP connection, as seen in a segment.
+type Flow = (IpEndpoint, IpEndpoint);
+
+/// Messages going one way across the wire.
+struct Direction {
+    dest: Index,
+    /// Messages waiting for the wire to be free, in one queue per flow.
+    /// The flow at the front transmits next, then goes to the back.
+    waiting: VecDeque<(Option<Flow>, VecDeque<Msg>)>,
+    /// The time the wire finishes transmitting the current message.
+    busy_until: Time,
+}
+
+impl Direction {
+    fn new(dest: Index) -> Direction {
+        Direction {
+            dest,
+            waiting: VecDeque::new(),
+            busy_until: 0,
+        }
+    }
+
+    /// The number of messages waiting.
+    fn len(&self) -> usize {
+        self.waiting.iter().map(|(_flow, queue)| queue.len()).sum()
+    }
+
+    fn push(&mut self, flow: Option<Flow>, msg: Msg) {
+        match self.waiting.iter_mut().find(|(f, _queue)| *f == flow) {
+            Some((_flow, queue)) => queue.push_back(msg),
+            None => self.waiting.push_back((flow, VecDeque::from([msg]))),
+        }
+    }
+
+    fn pop(&mut self) -> Option<Msg> {
+        let (flow, mut queue) = self.waiting.pop_front()?;
+        let msg = queue.pop_front();
+        if !queue.is_empty() {
+            self.waiting.push_back((flow, queue));
+        }
+        msg
+    }
+}
+
+/// The settings of a wire, apart from its ends, so many wires can be
+/// made alike with [`Wire::from_config`].
+///
+/// The default is a wire with no delay, jitter, loss, or bandwidth limit.
+#[derive(Clone, Debug, Default)]
+pub struct WireConfig {
+    pub delay: Time,
+    pub jitter: JitterModel,
+    /// Seeds the jitter and loss. Wires with the same config make the same
+    /// sequence of delays and lose the same messages.
+    pub seed: u64,
+    /// See [`Wire::set_loss`].
+    pub loss: f64,
+    /// See [`Wire::set_bandwidth`].
+    pub bandwidth: Option<u64>,
+    /// See [`Wire::set_buffer_limit`].
+    pub buffer_limit: Option<usize>,
+    pub discipline: QueueDiscipline,
+    /// See [`Wire::set_max_reorder_depth`].
+    pub max_reorder_depth: Option<usize>,
+}
 
 pub struct Wire {
     end1: Index,
     end2: Index,
     delay: Time,
-    outgoing: VecDeque<OutgoingMsg>,
+    delay_model: Box<dyn DelayModel>,
+    rng: Rng,
+    /// The probability that a message is lost on its way across.
+    loss: f64,
+    /// Bandwidth in bits per second, or `None` if messages
+    /// take no time to transmit.
+    bandwidth: Option<u64>,
+    /// The most messages that can wait to be transmitted
+    /// in each direction, if limited.
+    buffer_limit: Option<usize>,
+    discipline: QueueDiscipline,
+    /// Messages going to `end2` and `end1`, respectively.
+    directions: [Direction; 2],
+    /// Messages that have been transmitted and are propagating.
+    outgoing: BinaryHeap<OutgoingMsg>,
+    /// The sequence number given to the next message put on the wire.
+    next_seq: u64,
+    /// The number of messages dropped because the buffer was full.
+    dropped: usize,
+    /// The number of messages lost because of `loss`.
+    lost: usize,
+    /// The most later messages that can arrive before an earlier one,
+    /// if limited.
+    max_reorder_depth: Option<usize>,
+    /// How many later messages have arrived before each message in
+    /// `outgoing`, by sequence number. Only kept when reordering is limited.
+    overtaken: HashMap<u64, usize>,
+    events: BinaryHeap<Event<Wire>>,
+    /// The last time this wire was polled.
+    time: Time,
 }
 
 impl Wire {
     pub fn new(end1: Index, end2: Index, delay: Time) -> Wire {
+        Wire::with_jitter(end1, end2, delay, JitterModel::None, Rng::new(0))
+    }
+
+    /// Creates a wire whose delay varies according to `jitter`.
+    /// The jitter is drawn from `rng`, so the same seed always gives
+    /// the same sequence of delays.
+    pub fn with_jitter(
+        end1: Index,
+        end2: Index,
+        delay: Time,
+        jitter: JitterModel,
+        rng: Rng,
+    ) -> Wire {
         assert!(delay >= 0);
         Wire {
             end1,
             end2,
             delay,
-            outgoing: VecDeque::new(),
+            delay_model: Box::new(Jitter::new(jitter)),
+            rng,
+            loss: 0.0,
+            bandwidth: None,
+            buffer_limit: None,
+            discipline: QueueDiscipline::Fifo,
+            directions: [Direction::new(end2), Direction::new(end1)],
+            outgoing: BinaryHeap::new(),
+            next_seq: 0,
+            dropped: 0,
+            lost: 0,
+            max_reorder_depth: None,
+            overtaken: HashMap::new(),
+            events: BinaryHeap::new(),
+            time: 0,
+        }
+    }
+
+    /// Creates a wire between `end1` and `end2` with the given settings.
+    pub fn from_config(end1: Index, end2: Index, config: &WireConfig) -> Wire {
+        let mut wire = Wire::with_jitter(
+            end1,
+            end2,
+            config.delay,
+            config.jitter,
+            Rng::new(config.seed),
+        );
+        wire.set_loss(config.loss);
+        wire.set_bandwidth(config.bandwidth);
+        wire.set_buffer_limit(config.buffer_limit);
+        wire.set_queue_discipline(config.discipline);
+        wire.set_max_reorder_depth(config.max_reorder_depth);
+        wire
+    }
+
+    /// Schedule an event to occur on this wire,
+    /// like changing its bandwidth.
+    pub fn add_event(&mut self, time: Time, event: impl FnOnce(&mut Wire) + 'static) {
+        assert!(time >= self.time);
+        self.events.push(Event(time, Box::new(event)))
+    }
+
+    /// Replaces the model that picks each message's extra delay.
+    /// It draws from the wire's existing source of randomness.
+    pub fn set_delay_model(&mut self, model: impl DelayModel + 'static) {
+        self.delay_model = Box::new(model);
+    }
+
+    /// Sets the fixed delay of the wire. Messages already on their way keep
+    /// the delay they had, so lowering it can let later messages overtake them.
+    pub fn set_delay(&mut self, delay: Time) {
+        assert!(delay >= 0);
+        self.delay = delay;
+    }
+
+    /// Changes the fixed delay of the wire at the given time, e.g. to model
+    /// a latency spike by scheduling a jump up and then a change back.
+    /// See [`set_delay`](Wire::set_delay).
+    pub fn schedule_delay_change(&mut self, at: Time, new_delay: Time) {
+        assert!(new_delay >= 0);
+        self.add_event(at, move |wire| wire.set_delay(new_delay));
+    }
+
+    /// Sets the probability that each message is lost. A lost message
+    /// still takes up the wire while it's transmitted, but never arrives.
+    /// Losses are drawn from the wire's source of randomness.
+    pub fn set_loss(&mut self, probability: f64) {
+        assert!((0.0..=1.0).contains(&probability));
+        self.loss = probability;
+    }
+
+    /// Sets the bandwidth of the wire, in bits per second.
+    /// `None` means messages take no time to transmit.
+    ///
+    /// A message that is already being transmitted finishes at the old rate.
+    pub fn set_bandwidth(&mut self, bits_per_sec: Option<u64>) {
+        assert!(bits_per_sec != Some(0), "bandwidth must be positive");
+        self.bandwidth = bits_per_sec;
+    }
+
+    /// Limits how many messages can wait to be transmitted in each direction.
+    /// Messages that arrive when the buffer is full are dropped.
+    /// `None` removes the limit.
+    pub fn set_buffer_limit(&mut self, limit: Option<usize>) {
+        self.buffer_limit = limit;
+    }
+
+    /// Sets how the wire picks which waiting message to transmit next.
+    /// This only matters when the bandwidth is limited, since otherwise
+    /// messages never wait.
+    pub fn set_queue_discipline(&mut self, discipline: QueueDiscipline) {
+        self.discipline = discipline;
+    }
+
+    /// The number of messages waiting to be transmitted.
+    pub fn queued(&self) -> usize {
+        self.directions.iter().map(|dir| dir.len()).sum()
+    }
+
+    /// Limits how much jitter can reorder messages: once `depth` later
+    /// messages have arrived before an earlier one, the earlier one is
+    /// delivered (along with anything else sent before the next message)
+    /// as soon as the next message arrives, instead of at its own time.
+    ///
+    /// `Some(0)` keeps messages in order. `None` removes the limit.
+    pub fn set_max_reorder_depth(&mut self, depth: Option<usize>) {
+        self.max_reorder_depth = depth;
+    }
+
+    /// The number of messages dropped because the buffer was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// The number of messages lost on the way across.
+    /// See [`set_loss`](Wire::set_loss).
+    pub fn lost(&self) -> usize {
+        self.lost
+    }
+
+    /// How long it takes to transmit a message of the given length.
+    fn transmit_time(&self, len: usize) -> Time {
+        match self.bandwidth {
+            None => 0,
+            Some(bandwidth) => {
+                let bits = len as u64 * 8;
+                (bits * 1_000_000).div_ceil(bandwidth) as Time
+            }
+        }
+    }
+
+    /// Transmits waiting messages while the wire is free.
+    fn transmit(&mut self, time: Time) {
+        for i in 0..self.directions.len() {
+            while self.directions[i].busy_until <= time {
+                let Some(msg) = self.directions[i].pop() else {
+                    break;
+                };
+                let done = time + self.transmit_time(msg.len());
+                self.directions[i].busy_until = done;
+                // no loss leaves the random sequence the same as before
+                if self.loss > 0.0 && self.rng.chance(self.loss) {
+                    log!("wire lost a message to {}", self.directions[i].dest);
+                    self.lost += 1;
+                    continue;
+                }
+
+                let extra = self.delay_model.next_delay(done, &mut self.rng);
+                let arrival = done + self.delay + Time::max(0, extra);
+                let dest = self.directions[i].dest;
+                self.outgoing
+                    .push(OutgoingMsg(arrival, self.next_seq, dest, msg));
+                self.next_seq += 1;
+            }
         }
     }
+
+    /// Delivers a message, first forcing out any messages sent before it
+    /// that have been overtaken too many times.
+    fn deliver(&mut self, out: OutgoingMsg, result: &mut OutgoingMsgs) {
+        let OutgoingMsg(_, seq, dest, msg) = out;
+        self.overtaken.remove(&seq);
+        if let Some(depth) = self.max_reorder_depth {
+            let is_earlier = |other: &OutgoingMsg| other.2 == dest && other.1 < seq;
+            let force = self
+                .outgoing
+                .iter()
+                .filter(|other| is_earlier(other))
+                .any(|other| self.overtaken.get(&other.1).copied().unwrap_or(0) >= depth);
+
+            if force {
+                let (mut forced, rest): (Vec<_>, Vec<_>) = std::mem::take(&mut self.outgoing)
+                    .into_iter()
+                    .partition(|other| is_earlier(other));
+                self.outgoing = BinaryHeap::from(rest);
+                log!("wire forcing out {} reordered messages", forced.len());
+                forced.sort_by_key(|other| other.1);
+                for OutgoingMsg(_, forced_seq, forced_dest, forced_msg) in forced {
+                    self.overtaken.remove(&forced_seq);
+                    result.push((forced_dest, forced_msg));
+                }
+            } else {
+                for other in self.outgoing.iter().filter(|other| is_earlier(other)) {
+                    *self.overtaken.entry(other.1).or_insert(0) += 1;
+                }
+            }
+        }
+        result.push((dest, msg));
+    }
+
+    /// Returns the delivery time, destination, and length of every message
+    /// currently on the wire, in the order they will be delivered.
+    pub fn in_flight(&self) -> Vec<(Time, Index, usize)> {
+        Vec::from_iter(
+            self.in_flight_msgs()
+                .into_iter()
+                .map(|(time, dest, msg)| (time, dest, msg.len())),
+        )
+    }
+
+    /// Like [`in_flight`](Wire::in_flight), but includes the messages themselves.
+    pub fn in_flight_msgs(&self) -> Vec<(Time, Index, &Msg)> {
+        let mut msgs = Vec::from_iter(self.outgoing.iter());
+        // the heap is reversed, so this sorts earliest first
+        msgs.sort_by(|a, b| b.cmp(a));
+        Vec::from_iter(msgs.into_iter().map(|out| (out.0, out.2, &out.3)))
+    }
 }
 
 impl Node for Wire {
     fn poll(&mut self, time: Time, incoming: IncomingMsgs) -> OutgoingMsgs {
+        self.time = time;
+
+        // run scheduled events
+        while let Some(Event(event_time, _)) = self.events.peek() {
+            if *event_time <= time {
+                let ev = self.events.pop().unwrap();
+                (ev.1)(self);
+            } else {
+                break;
+            }
+        }
+
         for (sender, message) in incoming {
-            let dest = if sender == self.end1 {
-                self.end2
+            let direction = if sender == self.end1 {
+                &mut self.directions[0]
             } else if sender == self.end2 {
-                self.end1
+                &mut self.directions[1]
             } else {
                 panic!("Tried to send to invalid machine")
             };
 
-            let out_msg = OutgoingMsg(time + self.delay, dest, message);
-            self.outgoing.push_back(out_msg);
+            if self
+                .buffer_limit
+                .is_some_and(|limit| direction.len() >= limit)
+            {
+                log!("wire buffer full, dropping message from {sender}");
+                self.dropped += 1;
+                continue;
+            }
+            let flow = match self.discipline {
+                QueueDiscipline::Fifo => None,
+                QueueDiscipline::FairQueuing => tcp_flow(&message),
+            };
+            direction.push(flow, message);
         }
+        self.transmit(time);
 
         // Send outgoing messages
         let mut result = Vec::new();
-        while let Some(OutgoingMsg(out_time, _, _)) = self.outgoing.front() {
+        while let Some(OutgoingMsg(out_time, _, _, _)) = self.outgoing.peek() {
             if *out_time <= time {
-                let OutgoingMsg(_, dest, msg) = self.outgoing.pop_front().unwrap();
-                result.push((dest, msg));
+                let out = self.outgoing.pop().unwrap();
+                self.deliver(out, &mut result);
             } else {
                 break;
             }
@@ -54,6 +536,21 @@ impl Node for Wire {
     }
 
     fn poll_at(&mut self) -> Option<Time> {
-        self.outgoing.front().map(|out| out.0)
+        let arrivals = self.outgoing.peek().map(|out| out.0);
+        let transmits = self
+            .directions
+            .iter()
+            .filter(|dir| !dir.waiting.is_empty())
+            .map(|dir| dir.busy_until);
+        let events = self.next_event_time();
+        arrivals.into_iter().chain(transmits).chain(events).min()
+    }
+
+    fn is_link(&self) -> bool {
+        true
+    }
+
+    fn next_event_time(&self) -> Option<Time> {
+        self.events.peek().map(|event| event.0)
     }
 }
@@ -0,0 +1,67 @@
+use std::cell::Cell;
+
+use skys_elvis_impl::{
+    dhcp_server::DhcpServer, shared_medium::SharedMedium, simulator::run_sim_until,
+    tcp_machine::ElvOs, testing::SERVER_END,
+};
+use smoltcp::{
+    socket::tcp::State,
+    wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address, Ipv4Cidr},
+};
+
+thread_local! {
+    /// The address given to `got_address`.
+    static ADDRESS: Cell<Option<IpCidr>> = const { Cell::new(None) };
+}
+
+fn got_address(_os: &mut ElvOs, addr: IpCidr) {
+    ADDRESS.set(Some(addr));
+}
+
+#[test]
+fn client_gets_an_address_then_connects() {
+    // the client, the DHCP server, and another host share a medium
+    let client_mac = EthernetAddress([0, 0, 0, 0, 0, 1]);
+    let mut client = ElvOs::new(0, 3, client_mac);
+    client.enable_dhcp(got_address);
+    let pool = (100..110).map(|last| Ipv4Address([10, 0, 0, last]));
+    let mut dhcp = DhcpServer::new(
+        3,
+        EthernetAddress([0, 0, 0, 0, 0, 2]),
+        Ipv4Cidr::new(Ipv4Address([10, 0, 0, 254]), 24),
+        pool,
+    );
+    let mut server = ElvOs::new(0, 3, EthernetAddress([0, 0, 0, 0, 0, 3]));
+    server.set_local_addrs(IpCidr::new(SERVER_END.addr, 24));
+    let server_sock = server.socket();
+    server.listen(server_sock, SERVER_END).unwrap();
+    let mut medium = SharedMedium::new(vec![0, 1, 2], 100, 100_000_000);
+
+    ADDRESS.set(None);
+    run_sim_until(
+        &mut [&mut client, &mut dhcp, &mut server, &mut medium],
+        10_000_000,
+    );
+    let addr = ADDRESS.get().expect("the client should get an address");
+    let leased = IpAddress::Ipv4(Ipv4Address([10, 0, 0, 100]));
+    assert_eq!(addr, IpCidr::new(leased, 24));
+    assert_eq!(
+        dhcp.leases().get(&client_mac),
+        Some(&Ipv4Address([10, 0, 0, 100]))
+    );
+
+    let client_sock = client.socket();
+    client
+        .connect(client_sock, (leased, 50000), SERVER_END)
+        .unwrap();
+    run_sim_until(
+        &mut [&mut client, &mut dhcp, &mut server, &mut medium],
+        12_000_000,
+    );
+    assert_eq!(client.state(client_sock), State::Established);
+    assert_eq!(server.state(server_sock), State::Established);
+    assert_eq!(
+        server.remote_endpoint(server_sock).map(|end| end.addr),
+        Some(leased)
+    );
+}
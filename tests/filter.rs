@@ -0,0 +1,46 @@
+use skys_elvis_impl::{
+    filter::Filter,
+    rng::Rng,
+    simulator::{Simulation, Time},
+    stream::{StreamSender, StreamVerifier},
+    testing::{host, CLIENT_END, SERVER_END},
+};
+
+/// Sends 20 kB through a filter that loses pure ACKs with the given
+/// probability, and returns how long it took and how many ACKs were lost.
+fn transfer_with_ack_loss(ack_loss: f64) -> (Time, usize) {
+    let mut sender = StreamSender::new(
+        host(1, CLIENT_END.addr, 2),
+        CLIENT_END,
+        SERVER_END,
+        0,
+        20_000,
+    )
+    .unwrap();
+    let mut verifier = StreamVerifier::new(host(2, SERVER_END.addr, 2), SERVER_END, 0).unwrap();
+    let mut filter = Filter::new(0, 1, Rng::new(5));
+    filter.set_ack_loss(ack_loss);
+    filter.set_ack_delay(1000);
+
+    let mut sim = Simulation::new(vec![&mut sender, &mut verifier, &mut filter]);
+    let finished = sim.run_until_predicate(60_000_000, |nodes| {
+        nodes[1]
+            .downcast_ref::<StreamVerifier>()
+            .unwrap()
+            .finished()
+    });
+    assert!(finished, "the transfer should finish");
+    let time = sim.current_time();
+    drop(sim);
+    (time, filter.acks_lost())
+}
+
+#[test]
+fn lost_acks_slow_a_transfer_down() {
+    let (clean_time, clean_lost) = transfer_with_ack_loss(0.0);
+    assert_eq!(clean_lost, 0);
+    let (lossy_time, lossy_lost) = transfer_with_ack_loss(0.5);
+    // later ACKs cover for lost ones, except when the sender has to time out
+    assert!(lossy_lost > 0);
+    assert!(lossy_time > 10 * clean_time, "{clean_time} vs {lossy_time}");
+}
@@ -0,0 +1,20 @@
+use skys_elvis_impl::{
+    http::{HttpClient, HttpServer},
+    simulator::run_sim_until,
+    testing::{host, CLIENT_END, SERVER_END},
+    wire::Wire,
+};
+
+#[test]
+fn client_gets_the_whole_body() {
+    // more than fits in the server's send buffer at once
+    let body = Vec::from_iter((0..4000).map(|i| b'a' + (i % 26) as u8));
+    let mut server = HttpServer::new(host(2, SERVER_END.addr, 2), SERVER_END, &body).unwrap();
+    let mut client = HttpClient::new(host(1, CLIENT_END.addr, 2), CLIENT_END, SERVER_END).unwrap();
+    let mut wire = Wire::new(0, 1, 5000);
+
+    run_sim_until(&mut [&mut client, &mut server, &mut wire], 1_000_000);
+    let response = client.response().expect("the server should close");
+    assert!(response.starts_with(b"HTTP/1.0 200 OK\r\nContent-Length: 4000\r\n"));
+    assert_eq!(client.body(), Some(&body[..]));
+}
@@ -0,0 +1,89 @@
+use skys_elvis_impl::{
+    packet::{set_urgent, tcp_segment, urgent_pointer},
+    simulator::{IncomingMsgs, Index, Msg, Node, OutgoingMsgs, Simulation, Time},
+    testing::{host, CLIENT_END, SERVER_END},
+    wire::Wire,
+};
+use smoltcp::wire::{EthernetFrame, Ipv4Packet};
+
+/// Sits between the client and the wire, and marks the client's data
+/// segments as urgent.
+struct UrgentMarker {
+    client: Index,
+    wire: Index,
+    pointer: u16,
+}
+
+impl Node for UrgentMarker {
+    fn poll(&mut self, _time: Time, incoming: IncomingMsgs) -> OutgoingMsgs {
+        Vec::from_iter(incoming.into_iter().map(|(from, mut msg)| {
+            if from != self.client {
+                return (self.client, msg);
+            }
+            if tcp_segment(&msg).is_some_and(|tcp| !tcp.payload().is_empty()) {
+                assert!(set_urgent(&mut msg, Some(self.pointer)));
+            }
+            (self.wire, msg)
+        }))
+    }
+
+    fn poll_at(&mut self) -> Option<Time> {
+        None
+    }
+
+    fn is_link(&self) -> bool {
+        true
+    }
+}
+
+/// Whether the TCP checksum of the segment in the frame is right.
+fn checksum_valid(frame: &[u8]) -> bool {
+    let ip = Ipv4Packet::new_checked(EthernetFrame::new_checked(frame).unwrap().payload()).unwrap();
+    let tcp = tcp_segment(frame).unwrap();
+    tcp.verify_checksum(&ip.src_addr().into(), &ip.dst_addr().into())
+}
+
+#[test]
+fn urgent_segment_crosses_a_wire_intact() {
+    // the client sends through the marker (node 3), then the wire (node 2)
+    let mut client = host(1, CLIENT_END.addr, 3);
+    let mut server = host(2, SERVER_END.addr, 2);
+    let server_sock = server.socket();
+    server.listen(server_sock, SERVER_END).unwrap();
+    let client_sock = client.socket();
+    client.connect(client_sock, CLIENT_END, SERVER_END).unwrap();
+    client.add_event(100_000, move |os| {
+        os.send(client_sock, b"urgent!").unwrap();
+    });
+    let mut wire = Wire::new(3, 1, 1000);
+    let mut marker = UrgentMarker {
+        client: 0,
+        wire: 2,
+        pointer: 7,
+    };
+
+    let mut delivered: Vec<Msg> = Vec::new();
+    let mut sim = Simulation::new(vec![&mut client, &mut server, &mut wire, &mut marker]);
+    sim.set_observer(|_time, from, to, msg| {
+        if (from, to) == (2, 1) && tcp_segment(msg).is_some_and(|tcp| !tcp.payload().is_empty()) {
+            delivered.push(msg.clone());
+        }
+    });
+    sim.run_until(200_000);
+    drop(sim);
+
+    let [frame] = &delivered[..] else {
+        panic!("one data segment should arrive, got {}", delivered.len());
+    };
+    assert_eq!(urgent_pointer(frame), Some(7));
+    assert!(checksum_valid(frame));
+    assert_eq!(tcp_segment(frame).unwrap().payload(), b"urgent!");
+    // smoltcp takes urgent data as ordinary data
+    assert_eq!(server.recv(server_sock), b"urgent!");
+
+    // and clearing the flag again also leaves a valid segment
+    let mut frame = frame.clone();
+    assert!(set_urgent(&mut frame, None));
+    assert_eq!(urgent_pointer(&frame), None);
+    assert!(checksum_valid(&frame));
+}
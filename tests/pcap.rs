@@ -0,0 +1,186 @@
+use skys_elvis_impl::{
+    packet::tcp_segment,
+    pcap::{parse_pcap, PcapReplayNode},
+    simulator::{run_sim_until, Delivery, Time},
+    testing::{host, record_trace, CLIENT_END, SERVER_END},
+    wire::Wire,
+};
+use smoltcp::{socket::tcp::State, wire::EthernetFrame};
+
+/// A record: seconds, the fraction of a second, and the frame.
+type Record<'a> = (u32, u32, &'a [u8]);
+
+/// Builds a pcap file in the given byte order, with nanosecond
+/// timestamps if `nanos`, holding frames of the given link type.
+fn pcap(big_endian: bool, nanos: bool, link_type: u32, records: &[Record]) -> Vec<u8> {
+    let u32_bytes = |n: u32| {
+        if big_endian {
+            n.to_be_bytes()
+        } else {
+            n.to_le_bytes()
+        }
+    };
+    let magic: u32 = if nanos { 0xa1b2_3c4d } else { 0xa1b2_c3d4 };
+    let mut data = Vec::new();
+    data.extend(u32_bytes(magic));
+    // version 2.4
+    if big_endian {
+        data.extend([0, 2, 0, 4]);
+    } else {
+        data.extend([2, 0, 4, 0]);
+    }
+    // time zone, accuracy, and snapshot length
+    data.extend(u32_bytes(0));
+    data.extend(u32_bytes(0));
+    data.extend(u32_bytes(65535));
+    data.extend(u32_bytes(link_type));
+    for &(secs, fraction, frame) in records {
+        data.extend(u32_bytes(secs));
+        data.extend(u32_bytes(fraction));
+        data.extend(u32_bytes(frame.len() as u32));
+        data.extend(u32_bytes(frame.len() as u32));
+        data.extend(frame);
+    }
+    data
+}
+
+/// A little-endian pcap of ethernet frames, with microsecond timestamps.
+fn ethernet_pcap(records: &[Record]) -> Vec<u8> {
+    pcap(false, false, 1, records)
+}
+
+fn error_message(data: &[u8]) -> String {
+    parse_pcap(data).unwrap_err().to_string()
+}
+
+#[test]
+fn every_byte_order_and_precision_is_read() {
+    for big_endian in [false, true] {
+        for nanos in [false, true] {
+            let half = if nanos { 500_000_000 } else { 500_000 };
+            let data = pcap(
+                big_endian,
+                nanos,
+                1,
+                &[(10, 0, b"first"), (10, half, b"second"), (12, 0, b"third")],
+            );
+            let frames = parse_pcap(&data).unwrap();
+            assert_eq!(
+                frames,
+                vec![
+                    (0, b"first".to_vec()),
+                    (500_000, b"second".to_vec()),
+                    (2_000_000, b"third".to_vec()),
+                ],
+                "big endian: {big_endian}, nanoseconds: {nanos}"
+            );
+        }
+    }
+}
+
+#[test]
+fn nanoseconds_are_rounded_down_to_microseconds() {
+    let data = pcap(false, true, 1, &[(1, 0, b"a"), (1, 1_234_999, b"b")]);
+    let times = Vec::from_iter(parse_pcap(&data).unwrap().into_iter().map(|(time, _)| time));
+    assert_eq!(times, vec![0, 1234]);
+}
+
+#[test]
+fn empty_capture_has_no_frames() {
+    assert_eq!(parse_pcap(&ethernet_pcap(&[])).unwrap(), Vec::new());
+}
+
+#[test]
+fn bad_captures_are_errors() {
+    let data = ethernet_pcap(&[(1, 0, b"frame")]);
+    assert_eq!(error_message(&data[..20]), "pcap file is too short");
+
+    let mut not_pcap = data.clone();
+    not_pcap[0] = 0;
+    assert_eq!(error_message(&not_pcap), "not a pcap file");
+
+    let raw_ip = pcap(false, false, 101, &[(1, 0, b"frame")]);
+    assert_eq!(
+        error_message(&raw_ip),
+        "pcap file doesn't hold ethernet frames"
+    );
+
+    // in the middle of the record header, then of the frame
+    assert_eq!(error_message(&data[..30]), "pcap record header is cut off");
+    assert_eq!(
+        error_message(&data[..data.len() - 1]),
+        "pcap record is cut off"
+    );
+}
+
+#[test]
+fn frames_going_back_in_time_are_errors() {
+    let data = ethernet_pcap(&[(5, 0, b"a"), (4, 999_999, b"b")]);
+    assert_eq!(
+        error_message(&data),
+        "pcap record is older than the one before it"
+    );
+    // frames at the same time are fine
+    let data = ethernet_pcap(&[(5, 0, b"a"), (5, 0, b"b")]);
+    assert_eq!(parse_pcap(&data).unwrap().len(), 2);
+}
+
+/// Captures what a client sends to open a connection to `SERVER_END`:
+/// its ARP request and SYN, along with when it sent them.
+fn captured_handshake() -> Vec<(Time, Vec<u8>)> {
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+    let server_sock = server.socket();
+    server.listen(server_sock, SERVER_END).unwrap();
+    let client_sock = client.socket();
+    client.connect(client_sock, CLIENT_END, SERVER_END).unwrap();
+    let mut wire = Wire::new(0, 1, 1000);
+    let trace = record_trace(&mut [&mut client, &mut server, &mut wire], 100_000);
+    let sent = trace.into_iter().filter(|delivery| delivery.from == 0);
+    Vec::from_iter(sent.take(2).map(|Delivery { time, msg, .. }| (time, msg)))
+}
+
+#[test]
+fn replayed_syn_gets_a_syn_ack() {
+    let captured = captured_handshake();
+    let records = Vec::from_iter(
+        captured
+            .iter()
+            .map(|(time, frame)| (0, *time as u32, &frame[..])),
+    );
+    let frames = parse_pcap(&ethernet_pcap(&records)).unwrap();
+    assert_eq!(frames, captured);
+    let syn = tcp_segment(&frames[1].1).unwrap();
+    assert!(syn.syn() && !syn.ack());
+    let syn_seq = syn.seq_number();
+
+    // the server is node 0, and the replay node 1
+    let mut server = host(2, SERVER_END.addr, 1);
+    let sock = server.socket();
+    server.listen(sock, SERVER_END).unwrap();
+    let mut replay = PcapReplayNode::new(0, 10_000, frames);
+    let trace = record_trace(&mut [&mut server, &mut replay], 100_000);
+    assert_eq!(replay.remaining(), 0);
+
+    let replies = Vec::from_iter(trace.iter().filter(|delivery| delivery.from == 0));
+    let [arp_reply, syn_ack] = &replies[..] else {
+        panic!("expected an ARP reply and a SYN-ACK, got {replies:?}");
+    };
+    assert!(tcp_segment(&arp_reply.msg).is_none());
+    let syn_ack = tcp_segment(&syn_ack.msg).unwrap();
+    assert!(syn_ack.syn() && syn_ack.ack());
+    assert_eq!(syn_ack.ack_number(), syn_seq + 1);
+    assert_eq!(syn_ack.dst_port(), CLIENT_END.port);
+    // sent back to the client the capture came from
+    let client_mac = EthernetFrame::new_checked(&captured[1].1[..])
+        .unwrap()
+        .src_addr();
+    let reply_dst = EthernetFrame::new_checked(&replies[1].msg[..])
+        .unwrap()
+        .dst_addr();
+    assert_eq!(reply_dst, client_mac);
+
+    // the capture never finishes the handshake
+    run_sim_until(&mut [&mut server, &mut replay], 200_000);
+    assert_eq!(server.state(sock), State::SynReceived);
+}
@@ -0,0 +1,73 @@
+use std::{cell::RefCell, rc::Rc};
+
+use skys_elvis_impl::{
+    server::{ConnectionHandler, ConnectionManager},
+    shared_medium::SharedMedium,
+    simulator::{run_sim_until, Node},
+    testing::{host, SERVER_END},
+};
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+
+const CLIENTS: u8 = 3;
+
+/// Sends back whatever it gets.
+struct Echo;
+
+impl ConnectionHandler for Echo {
+    fn on_recv(&mut self, data: &[u8]) -> Vec<u8> {
+        data.to_vec()
+    }
+}
+
+#[test]
+fn several_clients_get_their_own_echo() {
+    let os = host(1, SERVER_END.addr, CLIENTS as usize + 1);
+    let peers = Rc::new(RefCell::new(Vec::new()));
+    let seen = peers.clone();
+    let mut manager = ConnectionManager::new(os, SERVER_END, 2, move |peer| {
+        seen.borrow_mut().push(peer);
+        Box::new(Echo)
+    })
+    .unwrap();
+
+    let replies = Rc::new(RefCell::new(Vec::new()));
+    let mut clients = Vec::from_iter((1..=CLIENTS).map(|k| {
+        let end = IpEndpoint::new(IpAddress::Ipv4(Ipv4Address([10, 0, 0, 10 + k])), 50000);
+        let mut os = host(k + 1, end.addr, CLIENTS as usize + 1);
+        let sock = os.socket();
+        let start = k as i64 * 10_000;
+        // staggered so the ARP requests don't collide on the medium
+        os.add_event(start, move |os| {
+            os.connect(sock, end, SERVER_END).unwrap();
+        });
+        os.add_event(start + 100_000, move |os| {
+            os.send(sock, format!("hello from {k}").as_bytes()).unwrap();
+        });
+        let replies = replies.clone();
+        os.add_event(start + 200_000, move |os| {
+            replies.borrow_mut().push((k, os.recv(sock)));
+            os.close(sock);
+        });
+        os
+    }));
+    let mut medium = SharedMedium::new(Vec::from_iter(0..=CLIENTS as usize), 100, 100_000_000);
+
+    let mut nodes: Vec<&mut dyn Node> = vec![&mut manager];
+    nodes.extend(clients.iter_mut().map(|os| os as &mut dyn Node));
+    nodes.push(&mut medium);
+    run_sim_until(&mut nodes, 1_000_000);
+
+    let replies = replies.borrow();
+    assert_eq!(replies.len(), CLIENTS as usize);
+    for (k, reply) in replies.iter() {
+        assert_eq!(reply, format!("hello from {k}").as_bytes());
+    }
+    let peers = peers.borrow();
+    assert_eq!(
+        Vec::from_iter(peers.iter().map(|peer| peer.addr)),
+        Vec::from_iter((1..=CLIENTS).map(|k| IpAddress::Ipv4(Ipv4Address([10, 0, 0, 10 + k]))))
+    );
+    // every connection was closed and its socket removed
+    assert_eq!(manager.connections().count(), 0);
+    assert_eq!(manager.closed(), CLIENTS as usize);
+}
@@ -0,0 +1,190 @@
+use std::{cell::RefCell, collections::HashSet};
+
+use skys_elvis_impl::{
+    packet::tcp_segment,
+    shared_medium::SharedMedium,
+    simulator::{run_sim_until, IncomingMsgs, Index, Msg, Node, OutgoingMsgs, Simulation, Time},
+    tcp_machine::ElvOs,
+    testing::host,
+};
+use smoltcp::{
+    iface::SocketHandle,
+    socket::tcp::State,
+    wire::{IpAddress, IpEndpoint, Ipv4Address},
+};
+
+/// The endpoint of host `i`, which is node `i`.
+fn end(i: u8, port: u16) -> IpEndpoint {
+    IpEndpoint::new(IpAddress::Ipv4(Ipv4Address([10, 0, 0, i + 1])), port)
+}
+
+#[test]
+fn partition_stops_only_crossing_connections() {
+    let mut hosts = [0, 1, 2, 3].map(|i| host(i + 1, end(i, 0).addr, 4));
+    let listeners = hosts.each_mut().map(|os| {
+        let sock = os.socket();
+        os.listen(sock, 80).unwrap();
+        sock
+    });
+    let mut medium = SharedMedium::new(vec![0, 1, 2, 3], 100, 100_000_000);
+    medium.partition(&[0, 1], &[2, 3]);
+
+    // 1 connects to 0 and 3 to 2 on their own sides, and 0 to 2 across,
+    // at different times so their ARP requests don't collide
+    let [h0, h1, h2, h3] = &mut hosts;
+    let inside_a = h1.socket();
+    h1.add_event(10_000, move |os| {
+        os.connect(inside_a, end(1, 50000), end(0, 80)).unwrap();
+    });
+    let inside_b = h3.socket();
+    h3.add_event(20_000, move |os| {
+        os.connect(inside_b, end(3, 50000), end(2, 80)).unwrap();
+    });
+    let across = h0.socket();
+    h0.add_event(30_000, move |os| {
+        os.connect(across, end(0, 50000), end(2, 80)).unwrap();
+    });
+    run_sim_until(&mut [h0, h1, h2, h3, &mut medium], 2_000_000);
+    assert_eq!(h1.state(inside_a), State::Established);
+    assert_eq!(h3.state(inside_b), State::Established);
+    assert_eq!(h0.state(across), State::SynSent);
+    assert_eq!(h0.state(listeners[0]), State::Established);
+    assert_eq!(h2.state(listeners[2]), State::Established);
+
+    // once healed, the SYN gets through when it's sent again
+    medium.heal();
+    let late_listener = h2.socket();
+    h2.listen(late_listener, 80).unwrap();
+    run_sim_until(&mut [h0, h1, h2, h3, &mut medium], 10_000_000);
+    assert_eq!(h0.state(across), State::Established);
+    assert_eq!(h2.remote_endpoint(late_listener), Some(end(0, 50000)));
+}
+
+/// Sends its messages to node `to` at the given time.
+struct Sender {
+    at: Time,
+    to: Index,
+    msgs: Vec<Msg>,
+}
+
+impl Node for Sender {
+    fn poll(&mut self, _time: Time, _incoming: IncomingMsgs) -> OutgoingMsgs {
+        Vec::from_iter(self.msgs.drain(..).map(|msg| (self.to, msg)))
+    }
+
+    fn poll_at(&mut self) -> Option<Time> {
+        (!self.msgs.is_empty()).then_some(self.at)
+    }
+}
+
+/// Remembers everything it gets.
+#[derive(Default)]
+struct Recorder {
+    received: Vec<Msg>,
+}
+
+impl Node for Recorder {
+    fn poll(&mut self, _time: Time, incoming: IncomingMsgs) -> OutgoingMsgs {
+        self.received
+            .extend(incoming.into_iter().map(|(_from, msg)| msg));
+        Vec::new()
+    }
+
+    fn poll_at(&mut self) -> Option<Time> {
+        None
+    }
+}
+
+/// Has nodes 1 and 2 each send a 1000 byte frame over a 1 Mbit/s medium,
+/// starting at the given times, and returns the frames node 0 got and
+/// the number of collisions.
+fn send_two_frames(first: Time, second: Time) -> (Vec<Msg>, usize) {
+    let mut recorder = Recorder::default();
+    let mut sender1 = Sender {
+        at: first,
+        to: 3,
+        msgs: vec![vec![1; 1000]],
+    };
+    let mut sender2 = Sender {
+        at: second,
+        to: 3,
+        msgs: vec![vec![2; 1000]],
+    };
+    let mut medium = SharedMedium::new(vec![0, 1, 2], 100, 1_000_000);
+    run_sim_until(
+        &mut [&mut recorder, &mut sender1, &mut sender2, &mut medium],
+        100_000,
+    );
+    (recorder.received, medium.collisions())
+}
+
+#[test]
+fn overlapping_frames_collide() {
+    // each frame takes 8 ms to send, so these overlap
+    let (received, collisions) = send_two_frames(1000, 5000);
+    assert!(received.is_empty());
+    assert_eq!(collisions, 1);
+
+    // and these don't
+    let (received, collisions) = send_two_frames(1000, 9000);
+    assert_eq!(received, vec![vec![1; 1000], vec![2; 1000]]);
+    assert_eq!(collisions, 0);
+}
+
+const LEN: usize = 100_000;
+
+thread_local! {
+    /// The length of every stream `got_stream` got.
+    static STREAMS: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A connect callback that sends `LEN` bytes and closes.
+fn send_stream(os: &mut ElvOs, sock: SocketHandle) {
+    assert_eq!(os.send(sock, &[7; LEN]).unwrap(), LEN);
+    os.close(sock);
+}
+
+fn got_stream(_os: &mut ElvOs, _sock: SocketHandle, data: Vec<u8>) {
+    STREAMS.with_borrow_mut(|streams| streams.push(data.len()));
+}
+
+#[test]
+fn transfers_finish_despite_collisions() {
+    // hosts 0 and 1 each send to their own server, 2 and 3,
+    // starting close enough together to overlap
+    let mut hosts = [0, 1, 2, 3].map(|i| host(i + 1, end(i, 0).addr, 4));
+    let [h0, h1, h2, h3] = &mut hosts;
+    for (i, client) in [h0, h1].into_iter().enumerate() {
+        let sock = client.socket_with_buffers(1500, LEN);
+        client.set_connect_callback(sock, send_stream);
+        let i = i as u8;
+        client.add_event(i as Time * 50_000, move |os| {
+            os.connect(sock, end(i, 50000), end(i + 2, 80)).unwrap();
+        });
+    }
+    for server in [h2, h3] {
+        let sock = server.socket();
+        server.listen(sock, 80).unwrap();
+        server.recv_until_eof(sock, got_stream);
+    }
+    let mut medium = SharedMedium::new(vec![0, 1, 2, 3], 100, 10_000_000);
+
+    let mut sent = HashSet::new();
+    let mut retransmits = 0;
+    let [h0, h1, h2, h3] = &mut hosts;
+    let mut sim = Simulation::new(vec![h0, h1, h2, h3, &mut medium]);
+    sim.set_observer(|_time, from, _to, msg| {
+        let Some(tcp) = tcp_segment(msg) else {
+            return;
+        };
+        if from < 2 && !tcp.payload().is_empty() && !sent.insert((from, tcp.seq_number().0)) {
+            retransmits += 1;
+        }
+    });
+    sim.run_until(60_000_000);
+    drop(sim);
+
+    assert_eq!(STREAMS.take(), vec![LEN, LEN]);
+    assert!(medium.collisions() > 0);
+    assert!(retransmits > 0);
+}
@@ -0,0 +1,392 @@
+use skys_elvis_impl::{
+    packet::tcp_segment,
+    shared_medium::SharedMedium,
+    simulator::{
+        run_sim_until_packets, try_run_sim_until, IncomingMsgs, MailboxOrder, Msg, Node, NodePolls,
+        OutgoingMsgs, Simulation, Time,
+    },
+    tcp_machine::ElvOs,
+    testing::{host, CLIENT_END, SERVER_END},
+    wire::Wire,
+};
+use smoltcp::{
+    socket::tcp::State,
+    wire::{IpAddress, IpEndpoint, Ipv4Address},
+};
+
+#[test]
+fn late_host_connects_to_an_existing_one() {
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+    let server_sock = server.socket();
+    server.listen(server_sock, SERVER_END).unwrap();
+    let late_sock = server.socket();
+    server.listen(late_sock, SERVER_END).unwrap();
+    let client_sock = client.socket();
+    client.connect(client_sock, CLIENT_END, SERVER_END).unwrap();
+    let mut medium = SharedMedium::new(vec![0, 1], 100, 100_000_000);
+    let late_end = IpEndpoint::new(IpAddress::Ipv4(Ipv4Address([10, 0, 0, 3])), 50000);
+    let mut late = host(3, late_end.addr, 2);
+
+    let mut sim = Simulation::new(vec![&mut client, &mut server, &mut medium]);
+    sim.run_until(100_000);
+    let index = sim.add_node(&mut late);
+    assert_eq!(index, 3);
+    sim.node(2)
+        .downcast_mut::<SharedMedium>()
+        .unwrap()
+        .attach(index);
+    let late = sim.node(index).downcast_mut::<ElvOs>().unwrap();
+    let sock = late.socket();
+    late.add_event(100_000, move |os| {
+        os.connect(sock, late_end, SERVER_END).unwrap();
+    });
+    sim.run_until(200_000);
+
+    let late = sim.node(index).downcast_mut::<ElvOs>().unwrap();
+    assert_eq!(late.state(sock), State::Established);
+    let server = sim.node(1).downcast_mut::<ElvOs>().unwrap();
+    assert_eq!(server.remote_endpoint(late_sock), Some(late_end));
+    assert_eq!(server.state(server_sock), State::Established);
+}
+
+#[test]
+#[should_panic(expected = "node 0 sent a message to node 5, which isn't in the simulation")]
+fn sending_to_a_missing_node_panics() {
+    let mut os = host(1, CLIENT_END.addr, 5);
+    let sock = os.socket();
+    os.connect(sock, CLIENT_END, SERVER_END).unwrap();
+    let mut sim = Simulation::new(vec![&mut os as &mut dyn Node]);
+    sim.run_until(100_000);
+}
+
+#[test]
+fn handshake_is_three_segments() {
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+    let server_sock = server.socket();
+    server.listen(server_sock, SERVER_END).unwrap();
+    let client_sock = client.socket();
+    client.connect(client_sock, CLIENT_END, SERVER_END).unwrap();
+    let mut wire = Wire::new(0, 1, 1000);
+
+    let packets = run_sim_until_packets(&mut [&mut client, &mut server, &mut wire], 5);
+    let summary = Vec::from_iter(packets.iter().map(|packet| {
+        let flags = tcp_segment(&packet.msg).map(|tcp| (tcp.syn(), tcp.ack()));
+        (packet.from, packet.to, flags)
+    }));
+    assert_eq!(
+        summary,
+        vec![
+            // ARP request and reply
+            (2, 1, None),
+            (2, 0, None),
+            // SYN, SYN-ACK, and ACK
+            (2, 1, Some((true, false))),
+            (2, 0, Some((true, true))),
+            (2, 1, Some((false, true))),
+        ]
+    );
+    // the server hasn't seen the ACK yet
+    assert_eq!(client.state(client_sock), State::Established);
+    assert_eq!(server.state(server_sock), State::SynReceived);
+}
+
+#[test]
+fn progress_is_reported_once_per_busy_interval() {
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+    let server_sock = server.socket();
+    server.listen(server_sock, SERVER_END).unwrap();
+    let client_sock = client.socket();
+    client.connect(client_sock, CLIENT_END, SERVER_END).unwrap();
+    // busy for the first 50 ms and from 200 to 250 ms, and quiet otherwise
+    for ms in (0..50).chain(200..250) {
+        client.add_event(ms * 1000, move |os| {
+            let _ = os.send(client_sock, b"tick");
+        });
+    }
+    let mut wire = Wire::new(0, 1, 100);
+
+    let mut reports = Vec::new();
+    let mut sim = Simulation::new(vec![&mut client, &mut server, &mut wire]);
+    sim.set_progress(10_000, |time| reports.push(time));
+    sim.run_until(300_000);
+    drop(sim);
+
+    // at most one report in each 10 ms, and none while nothing happens
+    let intervals = Vec::from_iter(reports.iter().map(|time| time / 10_000));
+    assert!(intervals.windows(2).all(|w| w[0] < w[1]), "{reports:?}");
+    for busy in (1..5).chain(20..25) {
+        assert!(intervals.contains(&busy), "{reports:?}");
+    }
+    assert!(
+        !intervals.iter().any(|i| (10..20).contains(i)),
+        "{reports:?}"
+    );
+}
+
+/// Sends itself a tick until it has received `limit` of them.
+struct SelfPinger {
+    limit: usize,
+    received: usize,
+}
+
+impl Node for SelfPinger {
+    fn poll(&mut self, _time: Time, incoming: IncomingMsgs) -> OutgoingMsgs {
+        self.received += incoming.len();
+        if self.received < self.limit {
+            vec![(0, b"tick".to_vec())]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn poll_at(&mut self) -> Option<Time> {
+        (self.received == 0).then_some(0)
+    }
+}
+
+#[test]
+fn node_can_send_itself_anything() {
+    let mut pinger = SelfPinger {
+        limit: 5,
+        received: 0,
+    };
+    let mut sim = Simulation::new(vec![&mut pinger as &mut dyn Node]);
+    sim.run_until(1000);
+    assert_eq!(sim.stats().packets_delivered, 5);
+    drop(sim);
+    assert_eq!(pinger.received, 5);
+}
+
+/// Sends its messages to node 0 at the given time.
+struct Sender {
+    at: Time,
+    msgs: Vec<Msg>,
+}
+
+impl Node for Sender {
+    fn poll(&mut self, _time: Time, _incoming: IncomingMsgs) -> OutgoingMsgs {
+        Vec::from_iter(self.msgs.drain(..).map(|msg| (0, msg)))
+    }
+
+    fn poll_at(&mut self) -> Option<Time> {
+        (!self.msgs.is_empty()).then_some(self.at)
+    }
+}
+
+/// Remembers the messages it got in each poll.
+#[derive(Default)]
+struct Recorder {
+    polls: Vec<IncomingMsgs>,
+}
+
+impl Node for Recorder {
+    fn poll(&mut self, _time: Time, incoming: IncomingMsgs) -> OutgoingMsgs {
+        if !incoming.is_empty() {
+            self.polls.push(incoming);
+        }
+        Vec::new()
+    }
+
+    fn poll_at(&mut self) -> Option<Time> {
+        None
+    }
+}
+
+/// Has nodes 1 and 2 send to node 0 at the same time, and returns the
+/// messages node 0 got in each poll.
+fn mailbox_polls(order: MailboxOrder) -> Vec<IncomingMsgs> {
+    let mut recorder = Recorder::default();
+    let mut first = Sender {
+        at: 1000,
+        msgs: vec![b"a".to_vec(), b"b".to_vec()],
+    };
+    let mut second = Sender {
+        at: 1000,
+        msgs: vec![b"c".to_vec()],
+    };
+    let mut sim = Simulation::new(vec![&mut recorder, &mut first, &mut second]);
+    sim.set_mailbox_order(order);
+    sim.run_until(2000);
+    drop(sim);
+    recorder.polls
+}
+
+#[test]
+fn messages_sent_at_once_arrive_in_a_fixed_order() {
+    let (a, b, c) = (b"a".to_vec(), b"b".to_vec(), b"c".to_vec());
+    let arrival = mailbox_polls(MailboxOrder::Arrival);
+    assert_eq!(
+        arrival,
+        vec![vec![(1, a.clone()), (1, b.clone())], vec![(2, c.clone())]]
+    );
+    let by_source = mailbox_polls(MailboxOrder::SourceIndex);
+    assert_eq!(by_source, vec![vec![(1, a), (1, b), (2, c)]]);
+
+    // and the same every time
+    assert_eq!(mailbox_polls(MailboxOrder::Arrival), arrival);
+    assert_eq!(mailbox_polls(MailboxOrder::SourceIndex), by_source);
+}
+
+#[test]
+fn next_event_time_includes_waiting_messages() {
+    // a host with nothing to do but an event
+    let mut os = host(1, CLIENT_END.addr, 1);
+    os.add_event(5000, |_os| {});
+    let mut sim = Simulation::new(vec![&mut os as &mut dyn Node]);
+    assert_eq!(sim.next_event_time(), Some(5000));
+    assert_eq!(sim.step(), Some((0, 5000)));
+    assert_eq!(sim.next_event_time(), None);
+    drop(sim);
+
+    let mut recorder = Recorder::default();
+    let mut sender = Sender {
+        at: 1000,
+        msgs: vec![b"a".to_vec()],
+    };
+    let mut sim = Simulation::new(vec![&mut recorder, &mut sender]);
+    assert_eq!(sim.next_event_time(), Some(1000));
+    assert_eq!(sim.step(), Some((1, 1000)));
+    // the message waiting for node 0 is delivered right away
+    assert_eq!(sim.next_event_time(), Some(1000));
+    assert_eq!(sim.step(), Some((0, 1000)));
+    assert_eq!(sim.next_event_time(), None);
+}
+
+/// Panics when it gets a message.
+struct Panicker;
+
+impl Node for Panicker {
+    fn poll(&mut self, _time: Time, incoming: IncomingMsgs) -> OutgoingMsgs {
+        if let Some((from, _msg)) = incoming.first() {
+            panic!("got a message from node {from}");
+        }
+        Vec::new()
+    }
+
+    fn poll_at(&mut self) -> Option<Time> {
+        None
+    }
+}
+
+#[test]
+fn panicking_node_is_reported() {
+    let mut panicker = Panicker;
+    let mut sender = Sender {
+        at: 1000,
+        msgs: vec![b"boom".to_vec()],
+    };
+    let err = try_run_sim_until(&mut [&mut panicker, &mut sender], 10_000).unwrap_err();
+    assert_eq!(err.node, 0);
+    assert_eq!(err.time, 1000);
+    assert_eq!(err.message, "got a message from node 1");
+    assert_eq!(err.incoming.len(), 1);
+    assert_eq!((err.incoming[0].from, err.incoming[0].to), (1, 0));
+    assert_eq!(err.incoming[0].msg, b"boom");
+}
+
+#[test]
+fn panics_while_delivering_are_blamed_on_the_sender() {
+    // a bad destination
+    let mut os = host(1, CLIENT_END.addr, 5);
+    let sock = os.socket();
+    os.connect(sock, CLIENT_END, SERVER_END).unwrap();
+    let err = try_run_sim_until(&mut [&mut os], 100_000).unwrap_err();
+    assert_eq!(err.node, 0);
+    assert_eq!(
+        err.message,
+        "node 0 sent a message to node 5, which isn't in the simulation"
+    );
+
+    // the observer
+    let mut recorder = Recorder::default();
+    let mut sender = Sender {
+        at: 1000,
+        msgs: vec![b"a".to_vec()],
+    };
+    let mut sim = Simulation::new(vec![&mut recorder, &mut sender]);
+    sim.set_observer(|_time, _from, _to, _msg| panic!("observer failed"));
+    let err = sim.try_run_until(10_000).unwrap_err();
+    assert_eq!((err.node, err.time), (1, 1000));
+    assert_eq!(err.message, "observer failed");
+    assert!(err.incoming.is_empty());
+    drop(sim);
+    assert!(recorder.polls.is_empty());
+}
+
+#[test]
+fn handshake_polls_are_counted_by_reason() {
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+    let server_sock = server.socket();
+    server.listen(server_sock, SERVER_END).unwrap();
+    let client_sock = client.socket();
+    client.add_event(1000, move |os| {
+        os.connect(client_sock, CLIENT_END, SERVER_END).unwrap();
+    });
+    let mut wire = Wire::new(0, 1, 1000);
+
+    let mut sim = Simulation::new(vec![&mut client, &mut server, &mut wire]);
+    sim.run_until(100_000);
+    let stats = sim.stats();
+    let polls = stats.polls;
+    drop(sim);
+    assert_eq!(client.state(client_sock), State::Established);
+    assert_eq!(server.state(server_sock), State::Established);
+    // the client is woken to connect, then gets the ARP reply and the
+    // SYN-ACK, and sends the SYN once the server's address is known
+    assert_eq!(
+        polls[0],
+        NodePolls {
+            mail: 2,
+            timer: 1,
+            event: 1
+        }
+    );
+    // the server only ever answers: the ARP request, SYN, and ACK
+    assert_eq!(
+        polls[1],
+        NodePolls {
+            mail: 3,
+            timer: 0,
+            event: 0
+        }
+    );
+    // the wire takes in each of the five packets, and is woken again
+    // when each one gets to the other end
+    assert_eq!(
+        polls[2],
+        NodePolls {
+            mail: 5,
+            timer: 5,
+            event: 0
+        }
+    );
+    // and every poll is counted exactly once
+    assert_eq!(polls.iter().map(NodePolls::total).sum::<u64>(), stats.steps);
+}
+
+#[test]
+fn corrupted_segment_shows_a_bad_checksum() {
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+    let server_sock = server.socket();
+    server.listen(server_sock, SERVER_END).unwrap();
+    let client_sock = client.socket();
+    client.connect(client_sock, CLIENT_END, SERVER_END).unwrap();
+    let mut wire = Wire::new(0, 1, 1000);
+    let packets = run_sim_until_packets(&mut [&mut client, &mut server, &mut wire], 3);
+
+    let syn = packets[2].to_string();
+    assert!(syn.contains("IPv4 checksum valid"), "{syn}");
+    assert!(syn.contains("TCP checksum valid"), "{syn}");
+
+    // change the window, past the Ethernet and IPv4 headers
+    let mut corrupted = packets[2].clone();
+    corrupted.msg[14 + 20 + 14] ^= 0xff;
+    let corrupted = corrupted.to_string();
+    assert!(corrupted.contains("IPv4 checksum valid"), "{corrupted}");
+    assert!(corrupted.contains("TCP checksum INVALID"), "{corrupted}");
+}
@@ -0,0 +1,94 @@
+use skys_elvis_impl::{
+    packet::reset_reply,
+    simulator::{run_sim_until, Simulation},
+    stream::{StreamSender, StreamVerifier},
+    testing::{host, CLIENT_END, SERVER_END},
+    wire::{JitterModel, Wire, WireConfig},
+};
+use smoltcp::socket::tcp::State;
+
+/// Sends `len` bytes of the stream with `sender_seed` to a verifier
+/// expecting the stream with `verifier_seed`, over a wire made from `config`.
+fn transfer(config: &WireConfig, len: usize, sender_seed: u64, verifier_seed: u64) -> usize {
+    let client = host(1, CLIENT_END.addr, 2);
+    let mut sender = StreamSender::new(client, CLIENT_END, SERVER_END, sender_seed, len).unwrap();
+    let server = host(2, SERVER_END.addr, 2);
+    let mut verifier = StreamVerifier::new(server, SERVER_END, verifier_seed).unwrap();
+    let mut wire = Wire::from_config(0, 1, config);
+    run_sim_until(&mut [&mut sender, &mut verifier, &mut wire], 120_000_000);
+    assert!(verifier.finished(), "{config:?}");
+    verifier.received()
+}
+
+#[test]
+fn stream_arrives_intact_over_a_bad_wire() {
+    let jittery = WireConfig {
+        delay: 2000,
+        jitter: JitterModel::Uniform { max: 10_000 },
+        seed: 1,
+        ..WireConfig::default()
+    };
+    let lossy = WireConfig {
+        delay: 2000,
+        seed: 2,
+        loss: 0.1,
+        ..WireConfig::default()
+    };
+    let both = WireConfig {
+        delay: 2000,
+        jitter: JitterModel::Uniform { max: 10_000 },
+        seed: 3,
+        loss: 0.1,
+        bandwidth: Some(10_000_000),
+        ..WireConfig::default()
+    };
+    for config in [jittery, lossy, both] {
+        assert_eq!(transfer(&config, 30_000, 7, 7), 30_000);
+    }
+}
+
+#[test]
+#[should_panic(expected = "stream differs at offset 0")]
+fn wrong_stream_fails_at_the_first_byte() {
+    transfer(&WireConfig::default(), 1000, 7, 8);
+}
+
+#[test]
+fn reset_stream_is_not_finished() {
+    const LEN: usize = 30_000;
+    let client = host(1, CLIENT_END.addr, 2);
+    let mut sender = StreamSender::new(client, CLIENT_END, SERVER_END, 7, LEN).unwrap();
+    let server = host(2, SERVER_END.addr, 2);
+    let mut verifier = StreamVerifier::new(server, SERVER_END, 7).unwrap();
+    // so the last ACK it sent is always for everything it got
+    let sock = verifier.sock();
+    verifier.os().set_ack_delay(sock, None);
+    let mut wire = Wire::new(0, 1, 1000);
+    wire.set_bandwidth(Some(1_000_000));
+
+    let mut last_from_verifier = None;
+    let mut sim = Simulation::new(vec![&mut sender, &mut verifier, &mut wire]);
+    sim.set_observer(|_time, from, _to, msg| {
+        if from == 1 {
+            last_from_verifier = Some(msg.clone());
+        }
+    });
+    let partway = sim.run_until_predicate(60_000_000, |nodes| {
+        nodes[1]
+            .downcast_ref::<StreamVerifier>()
+            .unwrap()
+            .received()
+            >= LEN / 3
+    });
+    assert!(partway);
+    let time = sim.current_time();
+    drop(sim);
+
+    // reset the connection as if the sender had
+    let reset = reset_reply(&last_from_verifier.unwrap()).unwrap();
+    verifier.os().inject_packet(reset);
+    run_sim_until(&mut [&mut sender, &mut verifier, &mut wire], time + 1000);
+    assert_eq!(verifier.os().state(sock), State::Closed);
+    assert!(verifier.received() < LEN);
+    assert!(!verifier.finished());
+}
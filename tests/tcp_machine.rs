@@ -0,0 +1,862 @@
+use std::cell::{Cell, RefCell};
+
+use skys_elvis_impl::{
+    filter::Filter,
+    packet::tcp_segment,
+    rng::Rng,
+    simulator::{run_sim_until, trace_to_string, Node, Simulation, Time},
+    tcp_machine::{ElvError, ElvOs, RecvPolicy, TIME_WAIT},
+    testing::{connect_pair, host, record_trace, ConnectedPair, CLIENT_END, SERVER_END},
+    wire::{JitterModel, Wire},
+};
+use smoltcp::{
+    iface::SocketHandle,
+    socket::tcp::State,
+    storage::Assembler,
+    wire::{
+        EthernetAddress, EthernetFrame, IpAddress, IpCidr, IpEndpoint, Ipv4Address, Ipv4Packet,
+        TcpPacket,
+    },
+};
+
+thread_local! {
+    /// Everything received by `collect`.
+    static RECEIVED: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A recv callback that adds what the socket received to `RECEIVED`.
+fn collect(os: &mut ElvOs, sock: SocketHandle) {
+    let data = os.recv(sock);
+    RECEIVED.with_borrow_mut(|received| received.extend(data));
+}
+
+#[test]
+fn listener_on_one_address_refuses_the_other() {
+    let other_addr = IpAddress::Ipv4(Ipv4Address([10, 0, 0, 3]));
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+    server.add_local_addr(IpCidr::new(other_addr, 24));
+
+    let listener = server.socket();
+    server.listen(listener, SERVER_END).unwrap();
+    let wrong = client.socket();
+    client
+        .connect(
+            wrong,
+            CLIENT_END,
+            IpEndpoint::new(other_addr, SERVER_END.port),
+        )
+        .unwrap();
+
+    let mut wire = Wire::new(0, 1, 1000);
+    run_sim_until(&mut [&mut client, &mut server, &mut wire], 100_000);
+    assert_eq!(client.state(wrong), State::Closed);
+    assert_eq!(server.state(listener), State::Listen);
+
+    // the listener still takes connections to its own address
+    // (once smoltcp is willing to send another ARP request, a second later)
+    let right = client.socket();
+    client
+        .connect(right, (CLIENT_END.addr, 50001), SERVER_END)
+        .unwrap();
+    run_sim_until(&mut [&mut client, &mut server, &mut wire], 2_000_000);
+    assert_eq!(client.state(right), State::Established);
+    assert_eq!(server.state(listener), State::Established);
+}
+
+#[test]
+fn half_close_stops_one_direction() {
+    let mut pair = connect_pair();
+    pair.client.close(pair.client_sock);
+    pair.run_until(100_000);
+
+    // the client can't send any more, but can still hear from the server
+    assert!(pair.client.is_open(pair.client_sock));
+    assert!(!pair.client.may_send(pair.client_sock));
+    assert!(pair.client.may_recv(pair.client_sock));
+    // and the other way around for the server
+    assert!(pair.server.is_open(pair.server_sock));
+    assert!(pair.server.may_send(pair.server_sock));
+    assert!(!pair.server.may_recv(pair.server_sock));
+
+    pair.server.close(pair.server_sock);
+    pair.run_until(200_000);
+    assert!(!pair.client.may_recv(pair.client_sock));
+    assert!(!pair.server.is_open(pair.server_sock));
+}
+
+#[test]
+fn frame_with_bad_checksum_is_dropped() {
+    let mut pair = connect_pair();
+    pair.server.set_recv_callback(pair.server_sock, collect);
+    RECEIVED.with_borrow_mut(Vec::clear);
+
+    pair.client.send(pair.client_sock, b"hello").unwrap();
+    let mut sent = Vec::new();
+    let mut sim = Simulation::new(vec![&mut pair.client, &mut pair.server, &mut pair.wire]);
+    sim.set_observer(|_time, from, _to, msg| {
+        if from == 0 && tcp_segment(msg).is_some_and(|tcp| !tcp.payload().is_empty()) {
+            sent.push(msg.clone());
+        }
+    });
+    sim.run_until(100_000);
+    drop(sim);
+    assert_eq!(RECEIVED.with_borrow(Vec::clone), b"hello");
+
+    // the next segment the client would send, but with different data
+    // than its checksum was computed for
+    let mut frame = sent[0].clone();
+    let mut eth = EthernetFrame::new_unchecked(&mut frame[..]);
+    let mut ip = Ipv4Packet::new_unchecked(eth.payload_mut());
+    let (src, dst) = (ip.src_addr(), ip.dst_addr());
+    let mut tcp = TcpPacket::new_unchecked(ip.payload_mut());
+    tcp.set_seq_number(tcp.seq_number() + 5);
+    tcp.payload_mut().copy_from_slice(b"XXXXX");
+    assert!(!tcp.verify_checksum(&src.into(), &dst.into()));
+    pair.server.inject_packet(frame);
+    pair.run_until(200_000);
+    assert_eq!(RECEIVED.with_borrow(Vec::clone), b"hello");
+
+    // the connection carries on as if nothing happened
+    pair.client.send(pair.client_sock, b"world").unwrap();
+    pair.run_until(300_000);
+    assert_eq!(RECEIVED.with_borrow(Vec::clone), b"helloworld");
+    assert_eq!(pair.server.state(pair.server_sock), State::Established);
+}
+
+/// Sends 10 kB from a client with the given egress rate to a server,
+/// and returns the times the client sent each data segment.
+fn data_send_times(egress_rate: Option<usize>) -> Vec<Time> {
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+    let server_sock = server.socket_with_buffers(16_384, 1500);
+    server.listen(server_sock, SERVER_END).unwrap();
+    let client_sock = client.socket_with_buffers(1500, 16_384);
+    client.connect(client_sock, CLIENT_END, SERVER_END).unwrap();
+    // so the last, small segment isn't held back waiting for an ACK
+    client.set_nagle_enabled(client_sock, false);
+    client.set_egress_rate(egress_rate);
+    client.add_event(10_000, move |os| {
+        os.send(client_sock, &[7; 10_000]).unwrap();
+    });
+
+    let mut wire = Wire::new(0, 1, 1000);
+    let mut times = Vec::new();
+    let mut sim = Simulation::new(vec![&mut client, &mut server, &mut wire]);
+    sim.set_observer(|time, from, _to, msg| {
+        if from == 0 && tcp_segment(msg).is_some_and(|tcp| !tcp.payload().is_empty()) {
+            times.push(time);
+        }
+    });
+    sim.run_until(100_000);
+    drop(sim);
+    times
+}
+
+#[test]
+fn egress_rate_spreads_packets_out() {
+    let unlimited = data_send_times(None);
+    assert!(unlimited.len() >= 7);
+    assert!(
+        unlimited.iter().all(|&time| time == unlimited[0]),
+        "{unlimited:?}"
+    );
+
+    let limited = data_send_times(Some(2));
+    assert_eq!(limited.len(), unlimited.len());
+    for ms in limited.chunk_by(|a, b| a / 1000 == b / 1000) {
+        assert!(ms.len() <= 2, "{limited:?}");
+    }
+    let last = limited.last().unwrap();
+    assert!(last - limited[0] >= 3000, "{limited:?}");
+}
+
+#[test]
+fn connecting_without_an_address_is_an_error() {
+    let mut os = ElvOs::new(0, 2, EthernetAddress([0, 0, 0, 0, 0, 1]));
+    let sock = os.socket();
+    assert_eq!(
+        os.connect(sock, CLIENT_END, SERVER_END),
+        Err(ElvError::NoLocalAddress)
+    );
+    assert_eq!(os.listen(sock, SERVER_END), Err(ElvError::NoLocalAddress));
+    assert_eq!(os.state(sock), State::Closed);
+
+    os.set_local_addrs(IpCidr::new(CLIENT_END.addr, 24));
+    assert_eq!(os.connect(sock, CLIENT_END, SERVER_END), Ok(()));
+}
+
+/// A recv callback that sends back whatever the socket received.
+fn echo(os: &mut ElvOs, sock: SocketHandle) {
+    let data = os.recv(sock);
+    let _ = os.send(sock, &data);
+}
+
+/// Runs a client sending to an echo server with many idle listening
+/// sockets over a jittery wire, and returns the trace.
+fn echo_trace(poll_at_cache: bool) -> String {
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+    client.set_poll_at_cache(poll_at_cache);
+    server.set_poll_at_cache(poll_at_cache);
+    for port in 1000..1050 {
+        let idle = server.socket();
+        server.listen(idle, port).unwrap();
+    }
+    let server_sock = server.socket();
+    server.listen(server_sock, SERVER_END).unwrap();
+    server.set_recv_callback(server_sock, echo);
+    let client_sock = client.socket();
+    client.connect(client_sock, CLIENT_END, SERVER_END).unwrap();
+    for i in 0..20 {
+        client.add_event(10_000 + i * 7_000, move |os| {
+            let _ = os.send(client_sock, &[i as u8; 700]);
+        });
+    }
+
+    let jitter = JitterModel::Uniform { max: 5000 };
+    let mut wire = Wire::with_jitter(0, 1, 1000, jitter, Rng::new(3));
+    let mut trace = record_trace(&mut [&mut client, &mut server, &mut wire], 300_000);
+    // sockets changed between polls have to be noticed too
+    client.send(client_sock, &[100; 1000]).unwrap();
+    trace.extend(record_trace(
+        &mut [&mut client, &mut server, &mut wire],
+        400_000,
+    ));
+    client.close(client_sock);
+    trace.extend(record_trace(
+        &mut [&mut client, &mut server, &mut wire],
+        500_000,
+    ));
+    server.close(server_sock);
+    trace.extend(record_trace(
+        &mut [&mut client, &mut server, &mut wire],
+        1_000_000,
+    ));
+    trace_to_string(&trace)
+}
+
+#[test]
+fn poll_at_cache_does_not_change_behavior() {
+    let cached = echo_trace(true);
+    assert!(cached.lines().count() > 100);
+    assert_eq!(cached, echo_trace(false));
+}
+
+/// Sends ten 1000-byte messages, 20 ms apart, from the client of `pair`
+/// to its server, which never reads, and runs until they're all sent.
+fn send_to_stopped_reader(pair: &mut ConnectedPair) {
+    pair.server.pause_recv(pair.server_sock, true);
+    let sock = pair.client_sock;
+    for i in 0..10 {
+        pair.client.add_event(10_000 + i * 20_000, move |os| {
+            // a full window makes this fail without the drop-on-full policy
+            let _ = os.send(sock, &[i as u8; 1000]);
+        });
+    }
+    pair.run_until(300_000);
+}
+
+#[test]
+fn drop_on_full_keeps_the_window_open() {
+    let mut pair = connect_pair();
+    pair.server
+        .set_recv_policy(pair.server_sock, RecvPolicy::DropOnFull { capacity: 3000 });
+    send_to_stopped_reader(&mut pair);
+
+    // everything was acknowledged, but only what fit in the queue was kept
+    assert_eq!(pair.client.send_queue(pair.client_sock), 0);
+    assert_eq!(pair.server.dropped_bytes(pair.server_sock), 7000);
+    assert_eq!(pair.server.recv_queue(pair.server_sock), 3000);
+    let info = pair.server.reassembly_info(pair.server_sock);
+    assert_eq!(info.contiguous, 3000);
+    let received = pair.server.recv(pair.server_sock);
+    assert_eq!(received, [[0; 1000], [1; 1000], [2; 1000]].concat());
+    assert_eq!(pair.server.state(pair.server_sock), State::Established);
+
+    // without the policy, the full window stops the client instead
+    let mut pair = connect_pair();
+    send_to_stopped_reader(&mut pair);
+    assert!(pair.client.send_queue(pair.client_sock) > 0);
+    assert_eq!(pair.server.dropped_bytes(pair.server_sock), 0);
+    assert_eq!(pair.server.recv_queue(pair.server_sock), 1500);
+}
+
+/// Sends two small messages in the same event, flushing between them if
+/// `flush` is set, and returns the payload and PSH flag of each data
+/// segment the client sent.
+fn segments_sent(flush: bool) -> Vec<(Vec<u8>, bool)> {
+    let mut pair = connect_pair();
+    let sock = pair.client_sock;
+    pair.client.add_event(10_000, move |os| {
+        os.send(sock, b"first").unwrap();
+        if flush {
+            os.flush(sock);
+        }
+        os.send(sock, b"second").unwrap();
+    });
+
+    let mut segments = Vec::new();
+    let mut sim = Simulation::new(vec![&mut pair.client, &mut pair.server, &mut pair.wire]);
+    sim.set_observer(|_time, from, _to, msg| {
+        if let Some(tcp) = tcp_segment(msg).filter(|tcp| from == 0 && !tcp.payload().is_empty()) {
+            segments.push((tcp.payload().to_vec(), tcp.psh()));
+        }
+    });
+    sim.run_until(100_000);
+    drop(sim);
+    segments
+}
+
+#[test]
+fn flush_sends_a_separate_segment() {
+    assert_eq!(segments_sent(false), vec![(b"firstsecond".to_vec(), true)]);
+    assert_eq!(
+        segments_sent(true),
+        vec![(b"first".to_vec(), true), (b"second".to_vec(), true)]
+    );
+}
+
+#[test]
+fn closing_when_drained_delivers_everything() {
+    let mut pair = connect_pair();
+    pair.server.set_recv_callback(pair.server_sock, collect);
+    RECEIVED.with_borrow_mut(Vec::clear);
+    pair.client
+        .set_drained_callback(pair.client_sock, ElvOs::close);
+
+    let msg = Vec::from_iter((0..1400).map(|i| i as u8));
+    pair.client.send(pair.client_sock, &msg).unwrap();
+    pair.run_until(100_000);
+    assert_eq!(RECEIVED.with_borrow(Vec::clone), msg);
+    assert!(!pair.client.may_send(pair.client_sock));
+    assert!(!pair.server.may_recv(pair.server_sock));
+
+    pair.server.close(pair.server_sock);
+    pair.run_until(200_000);
+    assert_eq!(pair.client.state(pair.client_sock), State::TimeWait);
+    assert_eq!(pair.server.state(pair.server_sock), State::Closed);
+    assert_eq!(RECEIVED.with_borrow(Vec::len), msg.len());
+}
+
+/// Sends one small message from the client to a server with the given
+/// ACK delay, and returns how long the server took to acknowledge it.
+fn ack_gap(ack_delay: Option<Time>) -> Time {
+    let mut pair = connect_pair();
+    pair.server.set_ack_delay(pair.server_sock, ack_delay);
+    let sock = pair.client_sock;
+    pair.client.add_event(10_000, move |os| {
+        os.send(sock, b"ping").unwrap();
+    });
+
+    let mut sent = None;
+    let mut acked = None;
+    let mut sim = Simulation::new(vec![&mut pair.client, &mut pair.server, &mut pair.wire]);
+    sim.set_observer(|time, from, _to, msg| {
+        let Some(tcp) = tcp_segment(msg) else {
+            return;
+        };
+        if from == 0 && !tcp.payload().is_empty() {
+            sent = Some((time, tcp.seq_number() + tcp.payload().len()));
+        } else if from == 1 && acked.is_none() {
+            if let Some((_, end)) = sent.filter(|_| tcp.ack()) {
+                if tcp.ack_number() == end {
+                    acked = Some(time);
+                }
+            }
+        }
+    });
+    sim.run_until(100_000);
+    drop(sim);
+    acked.expect("the message should be acknowledged") - sent.unwrap().0
+}
+
+#[test]
+fn ack_waits_for_the_ack_delay() {
+    assert_eq!(ack_gap(None), 0);
+    assert_eq!(ack_gap(Some(5000)), 5000);
+    assert_eq!(ack_gap(Some(10_000)), 10_000);
+    assert_eq!(ack_gap(Some(30_000)), 30_000);
+}
+
+#[test]
+fn simultaneous_open_connects_both_sides() {
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+    let client_sock = client.socket();
+    client.connect(client_sock, CLIENT_END, SERVER_END).unwrap();
+    let server_sock = server.socket();
+    server.connect(server_sock, SERVER_END, CLIENT_END).unwrap();
+
+    let mut wire = Wire::new(0, 1, 10_000);
+    let mut syns = Vec::new();
+    let mut sim = Simulation::new(vec![&mut client, &mut server, &mut wire]);
+    sim.set_observer(|time, from, to, msg| {
+        if tcp_segment(msg).is_some_and(|tcp| tcp.syn() && !tcp.ack()) && to == 2 {
+            syns.push((time, from));
+        }
+    });
+    sim.run_until(1_000_000);
+    drop(sim);
+
+    // the SYNs crossed on the wire
+    assert_eq!(syns.len(), 2, "{syns:?}");
+    assert_ne!(syns[0].1, syns[1].1);
+    assert!(syns[1].0 - syns[0].0 < 10_000, "{syns:?}");
+    assert_eq!(client.state(client_sock), State::Established);
+    assert_eq!(server.state(server_sock), State::Established);
+    assert_eq!(client.remote_endpoint(client_sock), Some(SERVER_END));
+    assert_eq!(server.remote_endpoint(server_sock), Some(CLIENT_END));
+}
+
+#[test]
+fn slow_reader_pushes_back_without_losing_data() {
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+    let server_sock = server.socket_with_buffers(2000, 1500);
+    server.listen(server_sock, SERVER_END).unwrap();
+    server.set_recv_callback(server_sock, collect);
+    server.pause_recv(server_sock, true);
+    let client_sock = client.socket_with_buffers(1500, 4000);
+    client.connect(client_sock, CLIENT_END, SERVER_END).unwrap();
+    let mut wire = Wire::new(0, 1, 5000);
+    RECEIVED.with_borrow_mut(Vec::clear);
+
+    let data = Vec::from_iter((0..10_000).map(|i| (i % 251) as u8));
+    run_sim_until(&mut [&mut client, &mut server, &mut wire], 100_000);
+    let mut sent = client.send(client_sock, &data).unwrap();
+    assert_eq!(sent, 4000);
+
+    // the server's window closes once its buffer is full
+    let mut windows = Vec::new();
+    let mut sim = Simulation::new(vec![&mut client, &mut server, &mut wire]);
+    sim.set_observer(|_time, from, _to, msg| {
+        if let Some(tcp) = tcp_segment(msg).filter(|_| from == 1) {
+            windows.push(tcp.window_len());
+        }
+    });
+    sim.run_until(200_000);
+    drop(sim);
+    assert_eq!(windows.last(), Some(&0), "{windows:?}");
+    assert_eq!(server.recv_queue(server_sock), 2000);
+    assert_eq!(client.send_queue(client_sock), 2000);
+    // only the acknowledged part of the send buffer is free again
+    assert_eq!(client.send(client_sock, &data[sent..]).unwrap(), 2000);
+    sent += 2000;
+    let full = client.send(client_sock, &data[sent..]);
+    assert_eq!(full.unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+
+    // once the server reads again, the rest gets through
+    server.pause_recv(server_sock, false);
+    let mut end_time = 200_000;
+    while sent < data.len() {
+        end_time += 50_000;
+        run_sim_until(&mut [&mut client, &mut server, &mut wire], end_time);
+        sent += client.send(client_sock, &data[sent..]).unwrap_or(0);
+    }
+    run_sim_until(
+        &mut [&mut client, &mut server, &mut wire],
+        end_time + 100_000,
+    );
+    assert_eq!(RECEIVED.with_borrow(Vec::clone), data);
+}
+
+/// Sends a message to an echo server with the given processing delay,
+/// over a wire with a 1 ms delay, and returns how long until the echo
+/// reaches the client.
+fn echo_rtt(processing_delay: Time) -> Time {
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+    let server_sock = server.socket();
+    server.listen(server_sock, SERVER_END).unwrap();
+    server.set_recv_callback(server_sock, echo);
+    server.set_processing_delay(processing_delay);
+    let client_sock = client.socket();
+    client.connect(client_sock, CLIENT_END, SERVER_END).unwrap();
+    client.add_event(100_000, move |os| {
+        os.send(client_sock, b"ping").unwrap();
+    });
+
+    let mut wire = Wire::new(0, 1, 1000);
+    let mut sent = None;
+    let mut echoed = None;
+    let mut sim = Simulation::new(vec![&mut client, &mut server, &mut wire]);
+    sim.set_observer(|time, from, to, msg| {
+        if tcp_segment(msg).is_some_and(|tcp| tcp.payload() == b"ping") {
+            match (from, to) {
+                (0, 2) => sent = sent.or(Some(time)),
+                (2, 0) => echoed = echoed.or(Some(time)),
+                _ => (),
+            }
+        }
+    });
+    sim.run_until(200_000);
+    drop(sim);
+    echoed.expect("the message should be echoed") - sent.unwrap()
+}
+
+#[test]
+fn processing_delay_adds_to_the_rtt() {
+    assert_eq!(echo_rtt(0), 2000);
+    assert_eq!(echo_rtt(3000), 5000);
+}
+
+#[test]
+fn stop_listening_refuses_only_new_connections() {
+    let mut pair = connect_pair();
+    let listener = pair.server.socket();
+    pair.server.listen(listener, SERVER_END).unwrap();
+    pair.server.stop_listening(listener);
+    assert_eq!(pair.server.state(listener), State::Closed);
+
+    let late = pair.client.socket();
+    pair.client
+        .connect(late, (CLIENT_END.addr, 50001), SERVER_END)
+        .unwrap();
+    pair.run_until(100_000);
+    assert_eq!(pair.client.state(late), State::Closed);
+
+    // the connection that was already there still works
+    pair.server.set_recv_callback(pair.server_sock, collect);
+    RECEIVED.with_borrow_mut(Vec::clear);
+    pair.client.send(pair.client_sock, b"still here").unwrap();
+    pair.run_until(200_000);
+    assert_eq!(RECEIVED.with_borrow(Vec::clone), b"still here");
+    assert_eq!(pair.server.state(pair.server_sock), State::Established);
+}
+
+#[test]
+fn full_buffer_would_block_but_unconnected_is_not_connected() {
+    use std::io::ErrorKind;
+
+    let mut pair = connect_pair();
+    pair.server.pause_recv(pair.server_sock, true);
+    // fill the server's window, then the client's send buffer
+    assert_eq!(
+        pair.client.send(pair.client_sock, &[1; 1500]).unwrap(),
+        1500
+    );
+    pair.run_until(100_000);
+    assert_eq!(
+        pair.client.send(pair.client_sock, &[2; 2000]).unwrap(),
+        1500
+    );
+    let full = pair.client.send(pair.client_sock, &[3; 10]).unwrap_err();
+    assert_eq!(full.kind(), ErrorKind::WouldBlock);
+    assert_eq!(pair.client.state(pair.client_sock), State::Established);
+
+    let never_connected = pair.client.socket();
+    let err = pair.client.send(never_connected, b"hi").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotConnected);
+
+    // a socket that has closed its side can't send either
+    pair.server.close(pair.server_sock);
+    let err = pair.server.send(pair.server_sock, b"hi").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotConnected);
+}
+
+/// Closes both sides of a connected pair, client first,
+/// so the client ends up in TIME-WAIT.
+fn close_pair() -> ConnectedPair {
+    let mut pair = connect_pair();
+    pair.client.close(pair.client_sock);
+    pair.run_until(50_000);
+    pair.server.close(pair.server_sock);
+    pair.run_until(100_000);
+    assert_eq!(pair.client.state(pair.client_sock), State::TimeWait);
+    assert_eq!(pair.server.state(pair.server_sock), State::Closed);
+    pair
+}
+
+#[test]
+fn time_wait_ends_or_is_cut_short_by_reconnecting() {
+    let mut pair = close_pair();
+    let remaining = pair.client.time_wait_remaining(pair.client_sock).unwrap();
+    assert!(remaining > TIME_WAIT - 100_000 && remaining <= TIME_WAIT);
+    pair.run_until(20_000_000);
+    assert_eq!(pair.client.state(pair.client_sock), State::Closed);
+    assert_eq!(pair.client.time_wait_remaining(pair.client_sock), None);
+
+    // smoltcp lets the same 4-tuple connect again without waiting
+    let mut pair = close_pair();
+    pair.server.listen(pair.server_sock, SERVER_END).unwrap();
+    pair.client
+        .connect(pair.client_sock, CLIENT_END, SERVER_END)
+        .unwrap();
+    pair.run_until(200_000);
+    assert_eq!(pair.client.state(pair.client_sock), State::Established);
+    assert_eq!(pair.server.state(pair.server_sock), State::Established);
+    assert_eq!(pair.client.time_wait_remaining(pair.client_sock), None);
+}
+
+#[test]
+fn reassembly_holds_the_gap_until_it_is_filled() {
+    // the client's three data segments, captured from one pair...
+    let mut pair = connect_pair();
+    pair.client.set_nagle_enabled(pair.client_sock, false);
+    let sock = pair.client_sock;
+    for i in 0..3u8 {
+        pair.client.add_event(10_000 + i as Time * 1000, move |os| {
+            os.send(sock, &[i; 100]).unwrap();
+        });
+    }
+    let mut segments = Vec::new();
+    let mut sim = Simulation::new(vec![&mut pair.client, &mut pair.server, &mut pair.wire]);
+    sim.set_observer(|_time, from, _to, msg| {
+        if from == 0 && tcp_segment(msg).is_some_and(|tcp| !tcp.payload().is_empty()) {
+            segments.push(msg.clone());
+        }
+    });
+    sim.run_until(100_000);
+    drop(sim);
+    assert_eq!(segments.len(), 3);
+
+    // ...are given to the server of an identical pair out of order
+    let mut pair = connect_pair();
+    pair.server.pause_recv(pair.server_sock, true);
+    pair.server.inject_packet(segments[0].clone());
+    pair.server.inject_packet(segments[2].clone());
+    pair.run_until(1000);
+    let info = pair.server.reassembly_info(pair.server_sock);
+    assert_eq!(info.contiguous, 100);
+    assert_eq!(info.ranges, vec![(100, 100)]);
+    assert_eq!(info.out_of_order(), 100);
+
+    pair.server.inject_packet(segments[1].clone());
+    pair.run_until(2000);
+    let info = pair.server.reassembly_info(pair.server_sock);
+    assert_eq!(info.contiguous, 300);
+    assert!(info.ranges.is_empty());
+    let expected = [[0; 100], [1; 100], [2; 100]].concat();
+    assert_eq!(pair.server.recv(pair.server_sock), expected);
+}
+
+#[test]
+fn smoltcp_still_shows_its_assembler() {
+    // `reassembly_info` reads this from the socket's debug output
+    let mut assembler = Assembler::new();
+    assembler.add(4, 2).unwrap();
+    let debug = format!("{assembler:?}");
+    assert!(
+        debug.contains("Contig { hole_size: 4, data_size: 2 }"),
+        "{debug}"
+    );
+}
+
+thread_local! {
+    /// Every buffer given to `got_everything`.
+    static EOF_BUFFERS: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+fn got_everything(_os: &mut ElvOs, _sock: SocketHandle, data: Vec<u8>) {
+    EOF_BUFFERS.with_borrow_mut(|buffers| buffers.push(data));
+}
+
+#[test]
+fn recv_until_eof_gets_the_whole_response_once() {
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+    let server_sock = server.socket_with_buffers(1500, 8000);
+    server.listen(server_sock, SERVER_END).unwrap();
+    let client_sock = client.socket();
+    client.connect(client_sock, CLIENT_END, SERVER_END).unwrap();
+    client.set_recv_callback(client_sock, collect);
+    client.recv_until_eof(client_sock, got_everything);
+    let mut wire = Wire::new(0, 1, 1000);
+    RECEIVED.with_borrow_mut(Vec::clear);
+    EOF_BUFFERS.with_borrow_mut(Vec::clear);
+
+    run_sim_until(&mut [&mut client, &mut server, &mut wire], 100_000);
+    let response = Vec::from_iter((0..5000).map(|i| (i % 253) as u8));
+    assert_eq!(server.send(server_sock, &response).unwrap(), 5000);
+    server.close(server_sock);
+    run_sim_until(&mut [&mut client, &mut server, &mut wire], 1_000_000);
+
+    // it took several segments, but the callback was called once
+    assert_eq!(EOF_BUFFERS.with_borrow(Vec::clone), vec![response]);
+    assert!(RECEIVED.with_borrow(Vec::is_empty));
+}
+
+thread_local! {
+    /// The peer given to `incoming`.
+    static INCOMING: Cell<Option<IpEndpoint>> = const { Cell::new(None) };
+    /// Whether `connected` was called.
+    static CONNECTED: Cell<bool> = const { Cell::new(false) };
+}
+
+fn incoming(_os: &mut ElvOs, _sock: SocketHandle, peer: IpEndpoint) {
+    INCOMING.set(Some(peer));
+}
+
+fn connected(_os: &mut ElvOs, _sock: SocketHandle) {
+    CONNECTED.set(true);
+}
+
+#[test]
+fn half_open_connection_calls_the_incoming_callback() {
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+    let server_sock = server.socket();
+    server.listen(server_sock, SERVER_END).unwrap();
+    server.set_incoming_callback(server_sock, incoming);
+    server.set_connect_callback(server_sock, connected);
+    let client_sock = client.socket();
+    client.connect(client_sock, CLIENT_END, SERVER_END).unwrap();
+    // the client's ACK of the SYN-ACK never arrives
+    let mut filter = Filter::new(0, 1, Rng::new(0));
+    filter.set_ack_loss(1.0);
+
+    INCOMING.set(None);
+    CONNECTED.set(false);
+    run_sim_until(&mut [&mut client, &mut server, &mut filter], 100_000);
+    assert_eq!(server.state(server_sock), State::SynReceived);
+    assert_eq!(INCOMING.get(), Some(CLIENT_END));
+    assert!(!CONNECTED.get());
+    assert!(filter.acks_lost() > 0);
+}
+
+#[test]
+fn flushing_leaves_injected_frames_for_the_poll() {
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut first_server = host(2, SERVER_END.addr, 2);
+    let listener = first_server.socket();
+    first_server.listen(listener, SERVER_END).unwrap();
+    let sock = client.socket();
+    client.connect(sock, CLIENT_END, SERVER_END).unwrap();
+    let mut wire = Wire::new(0, 1, 1000);
+    let trace = record_trace(&mut [&mut client, &mut first_server, &mut wire], 100_000);
+    let syn = trace
+        .into_iter()
+        .find(|delivery| tcp_segment(&delivery.msg).is_some_and(|tcp| tcp.syn()))
+        .unwrap()
+        .msg;
+
+    let mut server = host(2, SERVER_END.addr, 1);
+    let server_sock = server.socket();
+    server.listen(server_sock, SERVER_END).unwrap();
+    server.set_incoming_callback(server_sock, incoming);
+    INCOMING.set(None);
+    server.inject_packet(syn);
+    server.flush(server_sock);
+    // the SYN is only handled now, so the callback sees it arrive
+    assert_eq!(server.state(server_sock), State::Listen);
+    server.poll(0, Vec::new());
+    assert_eq!(server.state(server_sock), State::SynReceived);
+    assert_eq!(INCOMING.get(), Some(CLIENT_END));
+}
+
+const ROUND_BYTES: usize = 60_000;
+
+/// A connect callback that sends `ROUND_BYTES` and closes.
+fn send_round(os: &mut ElvOs, sock: SocketHandle) {
+    assert_eq!(os.send(sock, &[7; ROUND_BYTES]).unwrap(), ROUND_BYTES);
+    os.close(sock);
+}
+
+/// Connects to a server over a long fat wire `rounds` times, sending
+/// `ROUND_BYTES` each time, and returns how long each transfer took.
+fn round_times(autotune: Option<usize>, rounds: u16) -> Vec<Time> {
+    RECEIVED.with_borrow_mut(Vec::clear);
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+    let server_sock = server.socket_with_buffers(1500, 1500);
+    server.set_recv_callback(server_sock, collect);
+    server.set_recv_autotune(server_sock, autotune);
+    let mut wire = Wire::new(0, 1, 20_000);
+    wire.set_bandwidth(Some(100_000_000));
+
+    let mut times = Vec::new();
+    for round in 0..rounds {
+        // far enough apart for everything to be closed in between
+        let start = round as Time * 10_000_000;
+        run_sim_until(&mut [&mut client, &mut server, &mut wire], start);
+        assert_eq!(server.state(server_sock), State::Closed);
+        server.listen(server_sock, SERVER_END).unwrap();
+        let sock = client.socket_with_buffers(1500, ROUND_BYTES);
+        client.set_connect_callback(sock, send_round);
+        client.add_event(start, move |os| {
+            os.connect(sock, (CLIENT_END.addr, 50000 + round), SERVER_END)
+                .unwrap();
+        });
+        let mut sim = Simulation::new(vec![&mut client, &mut server, &mut wire]);
+        let done = sim.run_until_predicate(start + 5_000_000, |_nodes| {
+            RECEIVED.with_borrow(Vec::len) == ROUND_BYTES
+        });
+        assert!(done);
+        times.push(sim.current_time() - start);
+        drop(sim);
+        RECEIVED.with_borrow_mut(Vec::clear);
+        server.close(server_sock);
+    }
+    times
+}
+
+#[test]
+fn autotuning_speeds_up_later_transfers() {
+    let fixed = round_times(None, 4);
+    let tuned = round_times(Some(65_536), 4);
+    // the first connection has the small buffer either way
+    assert_eq!(tuned[0], fixed[0]);
+    // after that, the bigger window lets more data into each round trip
+    for round in 1..4 {
+        assert!(
+            tuned[round] * 3 < fixed[round] * 2,
+            "{tuned:?} vs {fixed:?}"
+        );
+    }
+}
+
+#[test]
+fn syn_flood_over_the_accept_rate_is_refused() {
+    const RATE: usize = 3;
+    const FLOOD: u16 = 10;
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+    server.set_accept_rate(Some(RATE));
+    let listeners = Vec::from_iter((0..FLOOD).map(|_| {
+        let sock = server.socket();
+        server.listen(sock, SERVER_END).unwrap();
+        sock
+    }));
+    let socks = Vec::from_iter((0..FLOOD).map(|i| {
+        let sock = client.socket();
+        client
+            .connect(sock, (CLIENT_END.addr, 50000 + i), SERVER_END)
+            .unwrap();
+        sock
+    }));
+    let mut wire = Wire::new(0, 1, 1000);
+    // well within the first second
+    run_sim_until(&mut [&mut client, &mut server, &mut wire], 500_000);
+
+    let states = Vec::from_iter(socks.iter().map(|&sock| client.state(sock)));
+    let established = states.iter().filter(|&&s| s == State::Established);
+    assert_eq!(established.count(), RATE, "{states:?}");
+    let refused = states.iter().filter(|&&s| s == State::Closed);
+    assert_eq!(refused.count(), FLOOD as usize - RATE, "{states:?}");
+    assert_eq!(server.refused_syns(), FLOOD as usize - RATE);
+    let states = Vec::from_iter(listeners.iter().map(|&sock| server.state(sock)));
+    let accepted = states.iter().filter(|&&s| s == State::Established);
+    assert_eq!(accepted.count(), RATE, "{states:?}");
+    let listening = states.iter().filter(|&&s| s == State::Listen);
+    assert_eq!(listening.count(), FLOOD as usize - RATE, "{states:?}");
+}
+
+#[test]
+fn socket_debug_leaves_out_the_buffers() {
+    let mut pair = connect_pair();
+    pair.client.send(pair.client_sock, &[171; 100]).unwrap();
+    let debug = pair.client.socket_debug(pair.client_sock);
+    for field in [
+        "state: Established",
+        "local_seq_no",
+        "remote_seq_no",
+        "remote_win_len",
+    ] {
+        assert!(debug.contains(field), "no {field} in {debug}");
+    }
+    // the data is in the send buffer, but only its length is shown
+    assert!(debug.contains("length: 100"), "{debug}");
+    assert_eq!(debug.matches("storage: ..,").count(), 2, "{debug}");
+    assert!(!debug.lines().any(|line| line.trim() == "171,"), "{debug}");
+}
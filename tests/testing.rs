@@ -0,0 +1,129 @@
+use std::panic;
+
+use skys_elvis_impl::{
+    simulator::Simulation,
+    testing::{
+        check_golden, check_trace, compare_transfers, connect_pair, host, TransferOptions,
+        CLIENT_END, SERVER_END,
+    },
+    wire::{Wire, WireConfig},
+};
+use smoltcp::socket::tcp::State;
+
+/// Where the golden files are kept.
+const GOLDEN: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden");
+
+#[test]
+fn loss_lowers_efficiency() {
+    let clean = WireConfig {
+        delay: 5000,
+        ..WireConfig::default()
+    };
+    let lossy = WireConfig {
+        loss: 0.1,
+        seed: 9,
+        ..clean.clone()
+    };
+    let options = [TransferOptions::default()];
+    let clean = &compare_transfers(&clean, 50_000, &options, 60_000_000)[0];
+    let lossy = &compare_transfers(&lossy, 50_000, &options, 60_000_000)[0];
+    assert!(clean.completion_time.is_some() && lossy.completion_time.is_some());
+    assert_eq!(clean.stats.goodput_bytes, 50_000);
+    assert_eq!(lossy.stats.goodput_bytes, 50_000);
+    assert_eq!(clean.retransmits, 0);
+    assert!(lossy.retransmits > 0);
+
+    // the same data, but more bytes on the wire to get it there
+    assert!(lossy.stats.wire_bytes > clean.stats.wire_bytes);
+    let (clean, lossy) = (clean.stats.efficiency(), lossy.stats.efficiency());
+    assert!(clean > 0.85, "{clean}");
+    assert!(lossy < clean, "{lossy} vs {clean}");
+}
+
+#[test]
+fn bigger_buffers_finish_sooner() {
+    let wire = WireConfig {
+        delay: 10_000,
+        ..WireConfig::default()
+    };
+    let small = TransferOptions::default();
+    let big = TransferOptions {
+        send_buffer: 16_384,
+        recv_buffer: 16_384,
+        ..small
+    };
+    let results = compare_transfers(&wire, 100_000, &[small, big], 60_000_000);
+    let [small, big] = &results[..] else {
+        panic!("there should be a result for each option");
+    };
+    assert_eq!(small.stats.goodput_bytes, 100_000);
+    assert_eq!(big.stats.goodput_bytes, 100_000);
+    let (small_time, big_time) = (small.completion_time.unwrap(), big.completion_time.unwrap());
+    // a window of one segment takes a round trip for each
+    assert!(big_time * 4 < small_time, "{big_time} vs {small_time}");
+    assert!(big.stats.steps < small.stats.steps);
+}
+
+#[test]
+fn handshake_matches_its_golden_trace() {
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+    let server_sock = server.socket();
+    server.listen(server_sock, SERVER_END).unwrap();
+    let client_sock = client.socket();
+    client.connect(client_sock, CLIENT_END, SERVER_END).unwrap();
+    let mut wire = Wire::new(0, 1, 1000);
+
+    check_trace(
+        &mut [&mut client, &mut server, &mut wire],
+        100_000,
+        format!("{GOLDEN}/handshake.txt"),
+    );
+}
+
+#[test]
+fn missing_golden_file_is_an_error() {
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        // it would be written instead
+        return;
+    }
+    let path = std::env::temp_dir().join("skys-elvis-impl-missing-golden.txt");
+    let result = panic::catch_unwind(|| check_golden(&path, "anything"));
+    let message = *result.unwrap_err().downcast::<String>().unwrap();
+    assert!(message.contains("doesn't exist"), "{message}");
+    assert!(!path.exists());
+}
+
+#[test]
+fn reconnecting_on_the_same_ports_counts_goodput_again() {
+    const ROUNDS: i64 = 6;
+    let mut pair = connect_pair();
+    let (client_sock, server_sock) = (pair.client_sock, pair.server_sock);
+    for round in 0..ROUNDS {
+        let start = (round + 1) * 100_000;
+        if round > 0 {
+            // straight out of TIME-WAIT, with a new ISN
+            pair.client.add_event(start, move |os| {
+                os.connect(client_sock, CLIENT_END, SERVER_END).unwrap();
+            });
+        }
+        pair.client.add_event(start + 20_000, move |os| {
+            assert_eq!(os.send(client_sock, &[1; 1000]).unwrap(), 1000);
+        });
+        pair.client
+            .add_event(start + 40_000, move |os| os.close(client_sock));
+        pair.server.add_event(start + 60_000, move |os| {
+            assert_eq!(os.recv(server_sock).len(), 1000);
+            os.close(server_sock);
+        });
+        pair.server.add_event(start + 80_000, move |os| {
+            os.listen(server_sock, SERVER_END).unwrap();
+        });
+    }
+    let mut sim = Simulation::new(vec![&mut pair.client, &mut pair.server, &mut pair.wire]);
+    sim.run_until((ROUNDS + 1) * 100_000);
+    let stats = sim.stats();
+    drop(sim);
+    assert_eq!(pair.client.state(client_sock), State::TimeWait);
+    assert_eq!(stats.goodput_bytes, ROUNDS as u64 * 1000);
+}
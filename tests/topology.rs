@@ -0,0 +1,130 @@
+use std::cell::Cell;
+
+use skys_elvis_impl::{tcp_machine::ElvOs, topology::Topology};
+use smoltcp::{iface::SocketHandle, socket::tcp::State};
+
+const PING_PONG: &str = "
+host client 10.0.0.1/24 02-00-00-00-00-01
+host server 10.0.0.2/24 02-00-00-00-00-02
+wire client server delay=5000 jitter=2000 seed=4 loss=0.1
+listen server 10.0.0.2:80
+connect 1000 client 10.0.0.1:50000 10.0.0.2:80
+";
+
+/// The number of pings and pongs each side sends before closing.
+const ROUNDS: usize = 10;
+
+thread_local! {
+    static PINGS: Cell<usize> = const { Cell::new(0) };
+    static PONGS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn send_ping(os: &mut ElvOs, sock: SocketHandle) {
+    os.send(sock, b"ping").unwrap();
+}
+
+fn got_pong(os: &mut ElvOs, sock: SocketHandle) {
+    for _pong in os.recv(sock).chunks(4) {
+        PONGS.set(PONGS.get() + 1);
+        if PONGS.get() < ROUNDS {
+            send_ping(os, sock);
+        } else {
+            os.close(sock);
+        }
+    }
+}
+
+fn got_ping(os: &mut ElvOs, sock: SocketHandle) {
+    for _ping in os.recv(sock).chunks(4) {
+        PINGS.set(PINGS.get() + 1);
+        os.send(sock, b"pong").unwrap();
+        if PINGS.get() == ROUNDS {
+            os.close(sock);
+        }
+    }
+}
+
+#[test]
+fn ping_pong_runs_to_completion() {
+    let mut topology = Topology::parse(PING_PONG).unwrap();
+    let client_sock = topology.socket("client", 50000).unwrap();
+    let server_sock = topology.socket("server", 80).unwrap();
+    let client = topology.host("client").unwrap();
+    client.set_connect_callback(client_sock, send_ping);
+    client.set_recv_callback(client_sock, got_pong);
+    topology
+        .host("server")
+        .unwrap()
+        .set_recv_callback(server_sock, got_ping);
+
+    PINGS.set(0);
+    PONGS.set(0);
+    topology.run_until(30_000_000);
+    assert_eq!(PINGS.get(), ROUNDS);
+    assert_eq!(PONGS.get(), ROUNDS);
+    assert!(topology.wires[0].lost() > 0);
+    let client = topology.host("client").unwrap();
+    assert!(matches!(
+        client.state(client_sock),
+        State::TimeWait | State::Closed
+    ));
+    let server = topology.host("server").unwrap();
+    assert_eq!(server.state(server_sock), State::Closed);
+}
+
+#[test]
+fn listen_and_connect_cant_share_a_port() {
+    let text = "
+host a 10.0.0.1/24 02-00-00-00-00-01
+host b 10.0.0.2/24 02-00-00-00-00-02
+wire a b
+listen a 10.0.0.1:80
+connect 0 a 10.0.0.1:80 10.0.0.2:80
+";
+    let err = Topology::parse(text).err().expect("the ports should clash");
+    assert_eq!(err.line, 6);
+    assert_eq!(err.message, "host a already has a socket on port 80");
+
+    let bad_loss = "
+host a 10.0.0.1/24 02-00-00-00-00-01
+host b 10.0.0.2/24 02-00-00-00-00-02
+wire a b loss=1.5
+";
+    let err = Topology::parse(bad_loss).err().unwrap();
+    assert_eq!(err.line, 4);
+}
+
+#[test]
+fn connect_lines_are_checked_when_parsing() {
+    let cases = [
+        (
+            "connect 0 a 10.0.0.1:0 10.0.0.2:80",
+            "connect needs nonzero ports",
+        ),
+        (
+            "connect 0 a 10.0.0.1:50000 10.0.0.2:0",
+            "connect needs nonzero ports",
+        ),
+        (
+            "connect 0 a 10.0.0.1:50000 0.0.0.0:80",
+            "can't connect to 0.0.0.0:80",
+        ),
+        (
+            "connect -5 a 10.0.0.1:50000 10.0.0.2:80",
+            "time can't be negative: -5",
+        ),
+    ];
+    for (connect, message) in cases {
+        let text = format!(
+            "
+host a 10.0.0.1/24 02-00-00-00-00-01
+host b 10.0.0.2/24 02-00-00-00-00-02
+wire a b
+{connect}
+"
+        );
+        let err = Topology::parse(&text).err().expect(connect);
+        assert_eq!(err.line, 5, "{connect}");
+        assert_eq!(err.message, message);
+    }
+}
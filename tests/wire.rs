@@ -0,0 +1,416 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use skys_elvis_impl::{
+    packet::tcp_segment,
+    rng::Rng,
+    simulator::{run_sim_until, Node, Simulation, Time},
+    stream::{StreamSender, StreamVerifier},
+    tcp_machine::ElvOs,
+    testing::{host, CLIENT_END, SERVER_END},
+    wire::{DelayModel, DelayTrace, JitterModel, QueueDiscipline, Wire, WireConfig},
+};
+use smoltcp::iface::SocketHandle;
+
+/// Whether the message is a SYN without an ACK, the first of a handshake.
+fn is_syn(msg: &[u8]) -> bool {
+    tcp_segment(msg).is_some_and(|tcp| tcp.syn() && !tcp.ack())
+}
+
+/// Puts `count` messages on the wire from node 0, one every millisecond,
+/// and returns when each will arrive.
+fn arrival_times(wire: &mut Wire, count: usize) -> Vec<Time> {
+    for i in 0..count {
+        let delivered = wire.poll(i as Time * 1000, vec![(0, vec![0; 64])]);
+        assert!(delivered.is_empty(), "the wire's delay should be longer");
+    }
+    Vec::from_iter(wire.in_flight().into_iter().map(|(time, _dest, _len)| time))
+}
+
+#[test]
+fn same_seed_gives_same_delays() {
+    let models = [
+        JitterModel::Uniform { max: 50_000 },
+        JitterModel::Normal {
+            mean: 20_000,
+            std_dev: 10_000,
+        },
+        JitterModel::GilbertElliott {
+            good_max: 1000,
+            bad_max: 80_000,
+            good_to_bad: 0.2,
+            bad_to_good: 0.3,
+        },
+    ];
+    for model in models {
+        let delays = |seed| {
+            let mut wire = Wire::with_jitter(0, 1, 1_000_000, model, Rng::new(seed));
+            arrival_times(&mut wire, 50)
+        };
+        assert_eq!(delays(7), delays(7), "{model:?}");
+        assert_ne!(delays(7), delays(8), "{model:?}");
+    }
+}
+
+#[test]
+fn in_flight_shows_the_syn_until_it_arrives() {
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+    let server_sock = server.socket();
+    server.listen(server_sock, SERVER_END).unwrap();
+    let client_sock = client.socket();
+    client.connect(client_sock, CLIENT_END, SERVER_END).unwrap();
+    let mut wire = Wire::new(0, 1, 10_000);
+    let mut sim = Simulation::new(vec![&mut client, &mut server, &mut wire]);
+
+    // ARP goes first, then the SYN
+    let wire_of = |sim: &mut Simulation| -> Vec<(Time, usize, Vec<u8>)> {
+        let wire = sim.node(2).downcast_mut::<Wire>().unwrap();
+        let msgs = wire.in_flight_msgs().into_iter();
+        Vec::from_iter(msgs.map(|(time, dest, msg)| (time, dest, msg.clone())))
+    };
+    while !wire_of(&mut sim).iter().any(|(_, _, msg)| is_syn(msg)) {
+        sim.step().unwrap();
+    }
+    let in_flight = wire_of(&mut sim);
+    assert_eq!(in_flight.len(), 1);
+    let (arrival, dest, syn) = &in_flight[0];
+    assert_eq!(*dest, 1);
+    let wire = sim.node(2).downcast_mut::<Wire>().unwrap();
+    assert_eq!(wire.in_flight(), vec![(*arrival, 1, syn.len())]);
+
+    sim.run_until(*arrival);
+    assert!(!wire_of(&mut sim).iter().any(|(_, _, msg)| is_syn(msg)));
+}
+
+#[test]
+fn lower_bandwidth_slows_a_transfer_down() {
+    let mut sender = StreamSender::new(
+        host(1, CLIENT_END.addr, 2),
+        CLIENT_END,
+        SERVER_END,
+        0,
+        1_000_000,
+    )
+    .unwrap();
+    let mut verifier = StreamVerifier::new(host(2, SERVER_END.addr, 2), SERVER_END, 0).unwrap();
+    let sock = verifier.sock();
+    verifier.os().set_ack_delay(sock, None);
+    let mut wire = Wire::new(0, 1, 1000);
+    wire.set_bandwidth(Some(1_000_000));
+    wire.add_event(500_000, |wire| wire.set_bandwidth(Some(500_000)));
+
+    // the bytes of data the server got in each 100 ms
+    let mut received = [0; 10];
+    let mut sim = Simulation::new(vec![&mut sender, &mut verifier, &mut wire]);
+    sim.set_observer(|time, _from, to, msg| {
+        if let Some(tcp) = tcp_segment(msg).filter(|_| to == 1) {
+            received[time as usize / 100_000] += tcp.payload().len();
+        }
+    });
+    sim.run_until(999_999);
+    drop(sim);
+
+    // halving the bandwidth roughly halves how fast data arrives
+    let before = received[2] + received[3] + received[4];
+    let after = received[7] + received[8] + received[9];
+    assert!(before > 20_000, "{received:?}");
+    assert!(after * 10 < before * 6, "{received:?}");
+    assert!(after * 10 > before * 4, "{received:?}");
+}
+
+/// Connects a client to a server over a wire with the given delay,
+/// and returns the client's handshake time.
+fn handshake_time(delay: Time) -> Time {
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+    let server_sock = server.socket();
+    server.listen(server_sock, SERVER_END).unwrap();
+    let client_sock = client.socket();
+    client.connect(client_sock, CLIENT_END, SERVER_END).unwrap();
+    let mut wire = Wire::new(0, 1, delay);
+    run_sim_until(&mut [&mut client, &mut server, &mut wire], 1_000_000);
+    client
+        .handshake_time(client_sock)
+        .expect("the client should connect")
+}
+
+#[test]
+fn handshake_time_scales_with_delay() {
+    // an ARP round trip, then the SYN and SYN-ACK
+    for delay in [1000, 10_000, 50_000] {
+        assert_eq!(handshake_time(delay), 4 * delay);
+    }
+}
+
+thread_local! {
+    /// The bytes received on each socket by `count_received`.
+    static RECEIVED: RefCell<HashMap<SocketHandle, usize>> = RefCell::new(HashMap::new());
+}
+
+/// A recv callback that counts the bytes received in `RECEIVED`.
+fn count_received(os: &mut ElvOs, sock: SocketHandle) {
+    let len = os.recv(sock).len();
+    RECEIVED.with_borrow_mut(|received| *received.entry(sock).or_default() += len);
+}
+
+/// Runs a transfer with a big window and one with a smaller window through
+/// a bottleneck with the given discipline, and returns how many bytes
+/// each got through in a second.
+fn big_and_small_transfers(discipline: QueueDiscipline) -> (usize, usize) {
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+    let mut transfer = |recv_size, send_size, port| {
+        let server_sock = server.socket_with_buffers(recv_size, 1500);
+        server.listen(server_sock, port).unwrap();
+        server.set_ack_delay(server_sock, None);
+        server.set_recv_callback(server_sock, count_received);
+        let client_sock = client.socket_with_buffers(1500, send_size);
+        let local = (CLIENT_END.addr, port + 50000);
+        client
+            .connect(client_sock, local, (SERVER_END.addr, port))
+            .unwrap();
+        // keep the send buffer full
+        for ms in 0..1000 {
+            client.add_event(ms * 1000, move |os| {
+                let _ = os.send(client_sock, &[0; 65_536]);
+            });
+        }
+        server_sock
+    };
+    let big = transfer(65_535, 65_536, 80);
+    let small = transfer(8000, 8000, 81);
+    let config = WireConfig {
+        delay: 1000,
+        bandwidth: Some(10_000_000),
+        buffer_limit: Some(50),
+        discipline,
+        ..WireConfig::default()
+    };
+    let mut wire = Wire::from_config(0, 1, &config);
+
+    RECEIVED.with_borrow_mut(HashMap::clear);
+    run_sim_until(&mut [&mut client, &mut server, &mut wire], 1_000_000);
+    let received = |sock| RECEIVED.with_borrow(|received| received[&sock]);
+    (received(big), received(small))
+}
+
+#[test]
+fn fair_queuing_shares_the_bandwidth() {
+    // the big window fills the queue, and the small one waits behind it
+    let (big, small) = big_and_small_transfers(QueueDiscipline::Fifo);
+    assert!(small * 5 < big, "{big} vs {small}");
+
+    let (big, small) = big_and_small_transfers(QueueDiscipline::FairQueuing);
+    assert!(big.abs_diff(small) * 10 < big + small, "{big} vs {small}");
+}
+
+/// Sends 200 numbered messages across a jittery wire with the given
+/// reordering limit, and returns, for each message, how many messages
+/// sent after it arrived first.
+fn times_overtaken(max_reorder_depth: Option<usize>) -> Vec<usize> {
+    let jitter = JitterModel::Uniform { max: 20_000 };
+    let mut wire = Wire::with_jitter(0, 1, 1000, jitter, Rng::new(11));
+    wire.set_max_reorder_depth(max_reorder_depth);
+    let mut arrived = Vec::new();
+    for i in 0..200u8 {
+        arrived.extend(wire.poll(i as Time * 1000, vec![(0, vec![i])]));
+    }
+    while let Some(time) = wire.poll_at() {
+        arrived.extend(wire.poll(time, Vec::new()));
+    }
+
+    let order = Vec::from_iter(arrived.into_iter().map(|(_dest, msg)| msg[0]));
+    assert_eq!(order.len(), 200);
+    Vec::from_iter((0..order.len()).map(|i| order[..i].iter().filter(|&&m| m > order[i]).count()))
+}
+
+#[test]
+fn reordering_depth_is_bounded() {
+    let unlimited = times_overtaken(None);
+    assert!(unlimited.iter().any(|&n| n > 3), "{unlimited:?}");
+
+    for depth in [0, 1, 3] {
+        let limited = times_overtaken(Some(depth));
+        assert!(limited.iter().all(|&n| n <= depth), "{depth}: {limited:?}");
+        if depth > 0 {
+            assert!(limited.contains(&depth), "{depth}: {limited:?}");
+        }
+    }
+
+    // a stream still arrives intact, even though its segments are reordered
+    let mut sender = StreamSender::new(
+        host(1, CLIENT_END.addr, 2),
+        CLIENT_END,
+        SERVER_END,
+        0,
+        50_000,
+    )
+    .unwrap();
+    let mut verifier = StreamVerifier::new(host(2, SERVER_END.addr, 2), SERVER_END, 0).unwrap();
+    let jitter = JitterModel::Uniform { max: 20_000 };
+    let mut wire = Wire::with_jitter(0, 1, 1000, jitter, Rng::new(11));
+    wire.set_max_reorder_depth(Some(2));
+    run_sim_until(&mut [&mut sender, &mut verifier, &mut wire], 30_000_000);
+    assert!(verifier.finished());
+    assert_eq!(verifier.received(), 50_000);
+}
+
+#[test]
+fn wires_from_one_config_behave_the_same() {
+    let config = WireConfig {
+        delay: 2000,
+        jitter: JitterModel::Uniform { max: 10_000 },
+        seed: 21,
+        loss: 0.3,
+        bandwidth: Some(10_000_000),
+        ..WireConfig::default()
+    };
+    // when each numbered message arrives, and how many were lost
+    let behavior = |config: &WireConfig, end1| {
+        let mut wire = Wire::from_config(end1, end1 + 1, config);
+        let mut arrivals = Vec::new();
+        for i in 0..100u8 {
+            let delivered = wire.poll(i as Time * 1000, vec![(end1, vec![i; 64])]);
+            arrivals.extend(
+                delivered
+                    .into_iter()
+                    .map(|(_, msg)| (i as Time * 1000, msg[0])),
+            );
+        }
+        while let Some(time) = wire.poll_at() {
+            let delivered = wire.poll(time, Vec::new());
+            arrivals.extend(delivered.into_iter().map(|(_, msg)| (time, msg[0])));
+        }
+        (arrivals, wire.lost())
+    };
+
+    let first = behavior(&config, 0);
+    assert!(first.1 > 10 && first.1 < 50, "lost {}", first.1);
+    assert_eq!(first.0.len() + first.1, 100);
+    assert_eq!(behavior(&config, 2), first);
+    assert_eq!(behavior(&config, 4), first);
+
+    let reseeded = WireConfig { seed: 22, ..config };
+    assert_ne!(behavior(&reseeded, 0), first);
+}
+
+/// A recv callback that sends back whatever the socket received.
+fn echo(os: &mut ElvOs, sock: SocketHandle) {
+    let data = os.recv(sock);
+    let _ = os.send(sock, &data);
+}
+
+#[test]
+fn delay_spike_has_three_phases() {
+    let mut client = host(1, CLIENT_END.addr, 2);
+    let mut server = host(2, SERVER_END.addr, 2);
+    let server_sock = server.socket();
+    server.listen(server_sock, SERVER_END).unwrap();
+    server.set_recv_callback(server_sock, echo);
+    let client_sock = client.socket();
+    client.connect(client_sock, CLIENT_END, SERVER_END).unwrap();
+    // so nothing but the wire holds the pings and echoes back
+    for (os, sock) in [(&mut client, client_sock), (&mut server, server_sock)] {
+        os.set_nagle_enabled(sock, false);
+        os.set_ack_delay(sock, None);
+    }
+    // a ping every 100 ms from 0.5 s to 3 s
+    for i in 5..30u8 {
+        client.add_event(i as Time * 100_000, move |os| {
+            os.send(client_sock, &[i; 8]).unwrap();
+        });
+    }
+    let mut wire = Wire::new(0, 1, 1000);
+    wire.schedule_delay_change(1_000_000, 50_000);
+    wire.schedule_delay_change(2_000_000, 1000);
+
+    // when each ping was first sent, and how long until it first came back,
+    // since the spike makes the client send some again
+    let mut sent = HashMap::new();
+    let mut rtts = HashMap::new();
+    let mut sim = Simulation::new(vec![&mut client, &mut server, &mut wire]);
+    sim.set_observer(|time, from, to, msg| {
+        let Some(tcp) = tcp_segment(msg).filter(|tcp| tcp.payload().len() == 8) else {
+            return;
+        };
+        let ping = tcp.payload()[0];
+        match (from, to) {
+            (0, 2) => {
+                sent.entry(ping).or_insert(time);
+            }
+            (2, 0) => {
+                rtts.entry(ping)
+                    .or_insert((sent[&ping], time - sent[&ping]));
+            }
+            _ => (),
+        }
+    });
+    sim.run_until(4_000_000);
+    drop(sim);
+
+    assert_eq!(rtts.len(), 25);
+    for (sent, rtt) in rtts.into_values() {
+        let expected = if (1_000_000..2_000_000).contains(&sent) {
+            100_000
+        } else {
+            2000
+        };
+        assert_eq!(rtt, expected, "ping sent at {sent}");
+    }
+}
+
+/// Adds 5 ms to every other message, and a random amount up to 100 µs
+/// to the rest.
+struct EveryOther {
+    slow: bool,
+}
+
+impl DelayModel for EveryOther {
+    fn next_delay(&mut self, _now: Time, rng: &mut Rng) -> Time {
+        self.slow = !self.slow;
+        if self.slow {
+            5000
+        } else {
+            rng.range(0, 100)
+        }
+    }
+}
+
+/// Puts `count` messages on the wire from node 0, 10 ms apart so each
+/// arrives before the next is sent, and returns the delay of each.
+fn delays(wire: &mut Wire, count: usize) -> Vec<Time> {
+    Vec::from_iter((0..count).map(|i| {
+        let now = i as Time * 10_000;
+        wire.poll(now, vec![(0, vec![0; 64])]);
+        let [(arrival, _dest, _len)] = wire.in_flight()[..] else {
+            panic!("only the last message should be on the wire");
+        };
+        arrival - now
+    }))
+}
+
+#[test]
+fn custom_delay_model_sets_the_delays() {
+    let mut wire = Wire::new(0, 1, 1000);
+    wire.set_delay_model(EveryOther { slow: false });
+    for (i, delay) in delays(&mut wire, 10).into_iter().enumerate() {
+        if i % 2 == 0 {
+            assert_eq!(delay, 6000);
+        } else {
+            assert!((1000..=1100).contains(&delay), "{delay}");
+        }
+    }
+
+    // closures work too, and see when each message leaves
+    let mut wire = Wire::new(0, 1, 1000);
+    wire.set_delay_model(|now: Time, _rng: &mut Rng| now / 10);
+    assert_eq!(delays(&mut wire, 4), vec![1000, 2000, 3000, 4000]);
+
+    // a trace is replayed in order, then from the start again
+    let mut wire = Wire::new(0, 1, 1000);
+    wire.set_delay_model(DelayTrace::new(vec![500, 0, 2000]));
+    assert_eq!(
+        delays(&mut wire, 7),
+        vec![1500, 1000, 3000, 1500, 1000, 3000, 1500]
+    );
+}